@@ -3,34 +3,15 @@ set -euo pipefail
 
 echo "[entrypoint.sh] Start"
 
-# Patch a config variable
-#
-# Args: <var> <type>
-#   var: The name of the config variable (e.g. `MAX_BACKUP_BYTES`)
-#   type: One of `string`, `number` or `boolean`
-function patch_var() {
-    var=$1
-    type=$2
+# Settings can be overridden with `SEKURSRANKO_*` env vars. For compatibility
+# with older images, the following unprefixed env vars are supported as well.
+for var in MAX_BACKUP_BYTES RETENTION_DAYS BACKUP_DIR LISTEN_ON ALLOW_BROWSER SWEEP_INTERVAL_SECS; do
+    prefixed="SEKURSRANKO_${var}"
     if [ -n "${!var:-}" ]; then
-        echo "[entrypoint.sh] Patching config ${var,,}"
-        if [ "$type" = "string" ]; then
-            sed "s%^${var,,}[ =].*$%${var,,} = \"${!var}\"%" /etc/sekursranko/config.toml | sponge /etc/sekursranko/config.toml
-        elif [ "$type" = "number" ]; then
-            sed "s%^${var,,}[ =].*$%${var,,} = ${!var}%" /etc/sekursranko/config.toml | sponge /etc/sekursranko/config.toml
-        elif [ "$type" = "boolean" ]; then
-            sed "s%^${var,,}[ =].*$%${var,,} = ${!var}%" /etc/sekursranko/config.toml | sponge /etc/sekursranko/config.toml
-        else
-            echo "[entrypoint.sh] Error: Invalid type \"$type\""
-            exit 1
-        fi
+        echo "[entrypoint.sh] Using ${var} for ${var,,}, please use ${prefixed} instead"
+        export "${prefixed}=${!var}"
     fi
-}
-
-patch_var MAX_BACKUP_BYTES number
-patch_var RETENTION_DAYS number
-patch_var BACKUP_DIR string
-patch_var LISTEN_ON string
-patch_var ALLOW_BROWSER boolean
+done
 
 echo "[entrypoint.sh] Done"
 exec sekursranko --config /etc/sekursranko/config.toml
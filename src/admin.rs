@@ -0,0 +1,318 @@
+//! The admin API.
+//!
+//! All admin requests must carry the configured `admin_token` as a bearer
+//! token. If no token is configured, the admin API is disabled.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use log::{error, info, warn};
+use route_recognizer::Params;
+use serde_derive::Serialize;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    handlers::{
+        backup_id_valid, response_404_not_found, response_405_method_not_allowed,
+        response_409_conflict, response_500_internal_server_error,
+    },
+    health,
+    routing::Route,
+    service::State,
+    stats::Stats,
+    storage::BackupMetadata,
+};
+
+/// The metadata of a backup or one of its versions.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionInfo {
+    /// The version number, or `None` for the current backup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    size: u64,
+    /// The time of the upload in seconds since the Unix epoch
+    modified: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+}
+
+impl VersionInfo {
+    fn new(version: Option<u32>, metadata: BackupMetadata) -> Self {
+        Self {
+            version,
+            size: metadata.size,
+            modified: unix_secs(metadata.modified),
+            etag: metadata.etag,
+        }
+    }
+}
+
+/// The response to a versions request.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Versions {
+    current: Option<VersionInfo>,
+    /// The previous versions, oldest first
+    versions: Vec<VersionInfo>,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A backup in the trash.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TrashInfo {
+    backup_id: String,
+    /// The time of the deletion in seconds since the Unix epoch
+    deleted: u64,
+    /// The time when the backup will be purged in seconds since the Unix epoch
+    purged: u64,
+    size: u64,
+    /// The time of the upload in seconds since the Unix epoch
+    modified: u64,
+}
+
+/// Handle a request to the admin API.
+pub(crate) async fn handle(
+    req: &Request<Body>,
+    state: &State,
+    route: Route,
+    params: &Params,
+) -> Response<Body> {
+    if let Some(response) = check_authorization(req, state) {
+        return response;
+    }
+    let backup_id = || params.find("backupId").expect("Missing backupId param");
+    match route {
+        Route::AdminVersions => handle_versions(req, state, backup_id()).await,
+        Route::AdminRestore => {
+            let version = params.find("version").expect("Missing version param");
+            handle_restore(req, state, backup_id(), version).await
+        }
+        Route::AdminTrash => handle_trash(req, state).await,
+        Route::AdminUntrash => handle_untrash(req, state, backup_id()).await,
+        Route::AdminStats => handle_stats(req, state).await,
+        Route::AdminReadiness => handle_readiness(req, state).await,
+        _ => unreachable!("Not an admin route: {:?}", route),
+    }
+}
+
+/// Check the bearer token of an admin request.
+///
+/// Return a response if the request is not authorized.
+fn check_authorization(req: &Request<Body>, state: &State) -> Option<Response<Body>> {
+    let config = state.config.get();
+    let admin_token = match &config.admin_token {
+        Some(admin_token) => admin_token,
+        None => return Some(response_404_not_found()),
+    };
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match token {
+        // Compare in constant time, to not leak the token through timing
+        Some(token)
+            if token.len() == admin_token.len()
+                && bool::from(token.as_bytes().ct_eq(admin_token.as_bytes())) =>
+        {
+            None
+        }
+        _ => {
+            warn!("Received admin request without valid token");
+            Some(
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::WWW_AUTHENTICATE, "Bearer")
+                    .body(Body::from("{\"detail\": \"Invalid admin token\"}"))
+                    .expect("Could not create response"),
+            )
+        }
+    }
+}
+
+fn json_response(body: &impl serde::Serialize) -> Response<Body> {
+    match serde_json::to_string(body) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("Could not create response"),
+        Err(e) => {
+            error!("Could not serialize admin response: {}", e);
+            response_500_internal_server_error()
+        }
+    }
+}
+
+/// List the current backup and all kept versions of a backup.
+async fn handle_versions(req: &Request<Body>, state: &State, backup_id: &str) -> Response<Body> {
+    if req.method() != Method::GET {
+        return response_405_method_not_allowed();
+    }
+    if !backup_id_valid(backup_id) {
+        return response_404_not_found();
+    }
+    let _guard = state.locks.read(backup_id).await;
+    let current = match state.store.stat(backup_id).await {
+        Ok(current) => current,
+        Err(e) => {
+            error!("Could not look up backup: {:#}", e);
+            return response_500_internal_server_error();
+        }
+    };
+    let versions = match state.store.versions(backup_id).await {
+        Ok(versions) => versions,
+        Err(e) => {
+            error!("Could not list versions of backup: {:#}", e);
+            return response_500_internal_server_error();
+        }
+    };
+    if current.is_none() && versions.is_empty() {
+        return response_404_not_found();
+    }
+    json_response(&Versions {
+        current: current.map(|metadata| VersionInfo::new(None, metadata)),
+        versions: versions
+            .into_iter()
+            .map(|v| VersionInfo::new(Some(v.version), v.metadata))
+            .collect(),
+    })
+}
+
+/// Replace a backup with one of its previous versions.
+async fn handle_restore(
+    req: &Request<Body>,
+    state: &State,
+    backup_id: &str,
+    version: &str,
+) -> Response<Body> {
+    if req.method() != Method::POST {
+        return response_405_method_not_allowed();
+    }
+    let version: u32 = match version.parse() {
+        Ok(version) if backup_id_valid(backup_id) => version,
+        _ => return response_404_not_found(),
+    };
+    let _guard = match state.locks.try_write(backup_id) {
+        Some(guard) => guard,
+        None => return response_409_conflict(),
+    };
+    match state.store.restore_version(backup_id, version).await {
+        Ok(true) => {
+            info!("Restored version {} of backup {}", version, backup_id);
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .expect("Could not create response")
+        }
+        Ok(false) => response_404_not_found(),
+        Err(e) => {
+            error!("Could not restore backup: {:#}", e);
+            response_500_internal_server_error()
+        }
+    }
+}
+
+/// List all backups in the trash.
+async fn handle_trash(req: &Request<Body>, state: &State) -> Response<Body> {
+    if req.method() != Method::GET {
+        return response_405_method_not_allowed();
+    }
+    let trash = match state.store.list_trash().await {
+        Ok(trash) => trash,
+        Err(e) => {
+            error!("Could not list trash: {:#}", e);
+            return response_500_internal_server_error();
+        }
+    };
+    let grace_period = u64::from(state.config.get().trash_days.unwrap_or(0)) * 24 * 3600;
+    let mut trash: Vec<TrashInfo> = trash
+        .into_iter()
+        .map(|(backup_id, trashed)| TrashInfo {
+            backup_id,
+            deleted: unix_secs(trashed.deleted),
+            purged: unix_secs(trashed.deleted) + grace_period,
+            size: trashed.metadata.size,
+            modified: unix_secs(trashed.metadata.modified),
+        })
+        .collect();
+    trash.sort_by(|a, b| a.backup_id.cmp(&b.backup_id));
+    json_response(&trash)
+}
+
+/// Move a backup from the trash back into place.
+async fn handle_untrash(req: &Request<Body>, state: &State, backup_id: &str) -> Response<Body> {
+    if req.method() != Method::POST {
+        return response_405_method_not_allowed();
+    }
+    if !backup_id_valid(backup_id) {
+        return response_404_not_found();
+    }
+    let _guard = match state.locks.try_write(backup_id) {
+        Some(guard) => guard,
+        None => return response_409_conflict(),
+    };
+    // A new backup might have been uploaded since the deletion
+    match state.store.head(backup_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body(Body::from("{\"detail\": \"Backup exists\"}"))
+                .expect("Could not create response")
+        }
+        Err(e) => {
+            error!("Could not look up backup: {:#}", e);
+            return response_500_internal_server_error();
+        }
+    }
+    match state.store.untrash(backup_id).await {
+        Ok(true) => {
+            info!("Restored backup {} from the trash", backup_id);
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .expect("Could not create response")
+        }
+        Ok(false) => response_404_not_found(),
+        Err(e) => {
+            error!("Could not restore backup from the trash: {:#}", e);
+            response_500_internal_server_error()
+        }
+    }
+}
+
+/// Report usage statistics of all stored backups.
+async fn handle_stats(req: &Request<Body>, state: &State) -> Response<Body> {
+    if req.method() != Method::GET {
+        return response_405_method_not_allowed();
+    }
+    match Stats::collect(
+        &*state.store,
+        state.config.get().retention_days,
+        SystemTime::now(),
+    )
+    .await
+    {
+        Ok(stats) => json_response(&stats),
+        Err(e) => {
+            error!("Could not collect backup statistics: {:#}", e);
+            response_500_internal_server_error()
+        }
+    }
+}
+
+/// Return the results of all readiness checks.
+async fn handle_readiness(req: &Request<Body>, state: &State) -> Response<Body> {
+    if req.method() != Method::GET {
+        return response_405_method_not_allowed();
+    }
+    json_response(&health::readiness(state).await)
+}
@@ -0,0 +1,211 @@
+//! Evaluation of conditional request headers (RFC 7232).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{
+    header::{self, HeaderMap},
+    Method, StatusCode,
+};
+
+use crate::storage::BackupMetadata;
+
+/// Return the quoted entity tag for the `ETag` header.
+pub fn quote_etag(etag: &str) -> String {
+    format!("\"{}\"", etag)
+}
+
+/// Return whether an `If-Match` or `If-None-Match` header value matches the
+/// current entity tag.
+///
+/// `*` matches any existing backup. With weak comparison, weak entity tags
+/// (`W/"..."`) match as well.
+fn etag_matches(header_value: &str, current: Option<&BackupMetadata>, weak: bool) -> bool {
+    let current = match current {
+        Some(current) => current,
+        None => return false,
+    };
+    if header_value.trim() == "*" {
+        return true;
+    }
+    let etag = match &current.etag {
+        Some(etag) => quote_etag(etag),
+        None => return false,
+    };
+    header_value.split(',').map(str::trim).any(|tag| {
+        let tag = match tag.strip_prefix("W/") {
+            Some(tag) if weak => tag,
+            Some(_) => return false,
+            None => tag,
+        };
+        tag == etag
+    })
+}
+
+/// Round a time down to whole seconds, the precision of HTTP dates.
+fn http_time(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Evaluate the conditional headers of a request against the current state
+/// of a backup.
+///
+/// Return the status code to respond with if a precondition is not met:
+/// `304 Not Modified` for GET and HEAD requests whose cached copy is still
+/// valid, or `412 Precondition Failed` otherwise.
+pub fn evaluate(
+    headers: &HeaderMap,
+    method: &Method,
+    current: Option<&BackupMetadata>,
+) -> Option<StatusCode> {
+    let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let is_get = method == Method::GET || method == Method::HEAD;
+
+    if let Some(if_match) = header_str(header::IF_MATCH) {
+        if !etag_matches(if_match, current, false) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if let Some(if_none_match) = header_str(header::IF_NONE_MATCH) {
+        if etag_matches(if_none_match, current, true) {
+            return Some(if is_get {
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::PRECONDITION_FAILED
+            });
+        }
+    } else if let (true, Some(current), Some(since)) = (
+        is_get,
+        current,
+        header_str(header::IF_MODIFIED_SINCE).and_then(|v| httpdate::parse_http_date(v).ok()),
+    ) {
+        if http_time(current.modified) <= since {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+
+    None
+}
+
+/// Return whether a `Range` header should be honored according to the
+/// `If-Range` header.
+///
+/// The range is only sent if the backup did not change since the client
+/// received the first part, as indicated by a strong entity tag or the
+/// modification date.
+pub fn if_range_matches(headers: &HeaderMap, current: &BackupMetadata) -> bool {
+    let value = match headers.get(header::IF_RANGE).map(|v| v.to_str()) {
+        Some(Ok(value)) => value.trim(),
+        Some(Err(_)) => return false,
+        None => return true,
+    };
+    if value.starts_with('"') {
+        current.etag.as_deref().map(quote_etag).as_deref() == Some(value)
+    } else if value.starts_with("W/") {
+        false
+    } else {
+        httpdate::parse_http_date(value)
+            .map(|date| http_time(current.modified) == date)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(etag: &str, modified_secs: u64) -> BackupMetadata {
+        BackupMetadata {
+            size: 0,
+            modified: UNIX_EPOCH + Duration::from_secs(modified_secs),
+            etag: Some(etag.to_string()),
+        }
+    }
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let current = metadata("abc", 0);
+        let eval = |value, method| {
+            evaluate(
+                &headers(header::IF_NONE_MATCH, value),
+                &method,
+                Some(&current),
+            )
+        };
+        assert_eq!(eval("\"abc\"", Method::GET), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(
+            eval("W/\"abc\"", Method::HEAD),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            eval("\"xyz\", \"abc\"", Method::GET),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(eval("\"xyz\"", Method::GET), None);
+        assert_eq!(
+            eval("*", Method::PUT),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            evaluate(&headers(header::IF_NONE_MATCH, "*"), &Method::PUT, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_if_match() {
+        let current = metadata("abc", 0);
+        let eval =
+            |value, current| evaluate(&headers(header::IF_MATCH, value), &Method::PUT, current);
+        assert_eq!(eval("\"abc\"", Some(&current)), None);
+        assert_eq!(eval("*", Some(&current)), None);
+        assert_eq!(
+            eval("W/\"abc\"", Some(&current)),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            eval("\"xyz\"", Some(&current)),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(eval("*", None), Some(StatusCode::PRECONDITION_FAILED));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        // 1994-11-06 08:49:37 UTC
+        let since = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let eval = |modified_secs| {
+            evaluate(
+                &headers(header::IF_MODIFIED_SINCE, since),
+                &Method::GET,
+                Some(&metadata("abc", modified_secs)),
+            )
+        };
+        assert_eq!(eval(784_111_777), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(eval(784_111_700), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(eval(784_111_778), None);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let current = metadata("abc", 784_111_777);
+        let eval = |value| if_range_matches(&headers(header::IF_RANGE, value), &current);
+        assert!(if_range_matches(&HeaderMap::new(), &current));
+        assert!(eval("\"abc\""));
+        assert!(!eval("\"xyz\""));
+        assert!(!eval("W/\"abc\""));
+        assert!(eval("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!eval("Sun, 06 Nov 1994 08:49:36 GMT"));
+        assert!(!eval("garbage"));
+    }
+}
@@ -2,12 +2,69 @@ use std::convert::From;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::iter;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
+use log::warn;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::{health, storage};
+
+/// The prefix of environment variables that override settings of the config
+/// file (e.g. `SEKURSRANKO_MAX_BACKUP_BYTES`).
+pub const ENV_PREFIX: &str = "SEKURSRANKO_";
+
+/// Environment variables with the `ENV_PREFIX` that are not settings.
+const ENV_RESERVED: &[&str] = &["SEKURSRANKO_CONFIG"];
+
+/// Settings that are no longer used, with a hint for the operator.
+///
+/// They are ignored with a warning instead of being rejected as unknown, so
+/// that existing config files keep working.
+const DEPRECATED_SETTINGS: &[(&str, &str)] =
+    &[("io_threads", "the server sizes its thread pool itself")];
+
+/// Settings that take effect when the config is reloaded.
+///
+/// All other settings are only used on startup, so they cannot be changed
+/// without a restart.
+const RELOADABLE_SETTINGS: &[&str] = &[
+    "max_backup_bytes",
+    "allow_browser",
+    "min_free_bytes",
+    "admin_token",
+];
+
+/// Settings with string or path values.
+///
+/// Overrides for these are never parsed as other TOML types, so that e.g. a
+/// token that only consists of digits stays a string.
+const STRING_SETTINGS: &[&str] = &[
+    "backup_dir",
+    "listen_on",
+    "tls_cert",
+    "tls_key",
+    "metrics_listen_on",
+    "admin_token",
+    "encryption_key_file",
+    "storage.backend",
+    "storage.path",
+    "storage.endpoint",
+    "storage.bucket",
+    "storage.prefix",
+    "storage.region",
+    "storage.access_key_id",
+    "storage.secret_access_key",
+];
+
+/// Settings whose values must not be logged.
+const SECRET_SETTINGS: &[&str] = &["admin_token", "storage"];
+
 /// The server configuration.
 #[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
     /// The max file size for backups (e.g. 65536)
     pub max_backup_bytes: u64,
@@ -22,28 +79,692 @@ pub struct ServerConfig {
     /// This will disable the user-agent check and set a CORS header on the
     /// response.
     pub allow_browser: Option<bool>,
+    /// The interval in seconds between two sweeps for expired backups
+    /// (default 3600)
+    pub sweep_interval_secs: Option<u64>,
+    /// The storage backend for backups (default filesystem)
+    pub storage: Option<StorageConfig>,
+    /// The path to a PEM encoded TLS certificate chain
+    ///
+    /// If this and `tls_key` are set, the server terminates TLS itself.
+    pub tls_cert: Option<PathBuf>,
+    /// The path to the PEM encoded private key for `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// The number of seconds a client may take for the TLS handshake
+    /// (default 10)
+    pub tls_handshake_timeout_secs: Option<u64>,
+    /// Rate limits for backup requests (default unlimited)
+    pub rate_limit: Option<RateLimitConfig>,
+    /// The listening address for the Prometheus metrics endpoint
+    /// (e.g. "127.0.0.1:9100")
+    ///
+    /// If this is not set, no metrics are exported.
+    pub metrics_listen_on: Option<String>,
+    /// The free space in bytes that must be available in the `backup_dir`
+    /// for the server to be ready (default `max_backup_bytes`)
+    pub min_free_bytes: Option<u64>,
+    /// The number of seconds to wait for in-flight requests to finish on
+    /// shutdown (default 30)
+    pub shutdown_timeout_secs: Option<u64>,
+    /// The age in seconds after which temporary files of interrupted uploads
+    /// are considered orphaned and removed (default 3600)
+    pub orphan_max_age_secs: Option<u64>,
+    /// The number of previous versions to keep per backup (default 0)
+    ///
+    /// Previous versions are only accessible through the admin API.
+    pub keep_versions: Option<u32>,
+    /// The number of days deleted backups are kept in the trash before they
+    /// are purged (default 0)
+    ///
+    /// If this is 0, backups are deleted immediately. Backups in the trash can
+    /// be restored through the admin API.
+    pub trash_days: Option<u32>,
+    /// The bearer token for the admin API
+    ///
+    /// If this is not set, the admin API is disabled.
+    pub admin_token: Option<String>,
+    /// The path to a file with the keys for encrypting backups at rest
+    ///
+    /// Every line holds a key of 64 hex characters. New backups are encrypted
+    /// with the first key, the others are only used for reading. If this is
+    /// not set, backups are stored as uploaded.
+    pub encryption_key_file: Option<PathBuf>,
+}
+
+/// The storage backend configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StorageConfig {
+    /// Store backups as files in the `backup_dir`
+    Filesystem(FilesystemConfig),
+    /// Store backups in memory, they will be lost when the server stops
+    Memory,
+    /// Store backups in an S3 compatible object storage
+    S3(S3Config),
+    /// Store backups in a single SQLite database
+    Sqlite(SqliteConfig),
+}
+
+/// The SQLite storage backend configuration.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SqliteConfig {
+    /// The path to the database file (default `backups.sqlite3` in the
+    /// `backup_dir`)
+    pub path: Option<PathBuf>,
+}
+
+impl SqliteConfig {
+    /// Return the path to the database file.
+    pub fn path(&self, backup_dir: &Path) -> PathBuf {
+        match &self.path {
+            Some(path) => path.clone(),
+            None => backup_dir.join("backups.sqlite3"),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Filesystem(FilesystemConfig::default())
+    }
+}
+
+/// The filesystem storage backend configuration.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilesystemConfig {
+    /// Whether to store backups in two levels of subdirectories derived from
+    /// the backup id (e.g. `01/23/0123...`) instead of a single directory
+    pub sharded: Option<bool>,
+    /// Whether to flush backups to disk before acknowledging an upload
+    /// (default false)
+    ///
+    /// This makes sure that acknowledged backups survive a power loss, at
+    /// the cost of slower uploads.
+    pub fsync: Option<bool>,
+}
+
+/// The rate limiting configuration.
+///
+/// Every client IP gets a separate token bucket per request type. Request
+/// types without a limit are not throttled.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// The number of downloads (GET and HEAD) per minute
+    pub download_per_minute: Option<u32>,
+    /// The number of uploads (PUT) per minute
+    pub upload_per_minute: Option<u32>,
+    /// The number of deletions (DELETE) per minute
+    pub delete_per_minute: Option<u32>,
+    /// The number of requests that may be made at once before being
+    /// throttled (default the per minute limit)
+    pub burst: Option<u32>,
+    /// Whether to additionally limit requests per backup id, regardless of
+    /// the client IP
+    pub per_backup_id: Option<bool>,
+}
+
+/// The S3 storage backend configuration.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// The endpoint URL (e.g. "https://s3.eu-central-1.amazonaws.com")
+    pub endpoint: String,
+    /// The name of the bucket
+    pub bucket: String,
+    /// A prefix for all object keys (e.g. "backups/")
+    pub prefix: Option<String>,
+    /// The region of the bucket (default "us-east-1")
+    pub region: Option<String>,
+    /// The access key id
+    pub access_key_id: String,
+    /// The secret access key
+    pub secret_access_key: String,
+}
+
+impl fmt::Display for StorageConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageConfig::Filesystem(fs) => write!(
+                f,
+                "filesystem ({}{})",
+                if fs.sharded.unwrap_or(false) {
+                    "sharded"
+                } else {
+                    "flat"
+                },
+                if fs.fsync.unwrap_or(false) {
+                    ", fsync"
+                } else {
+                    ""
+                }
+            ),
+            StorageConfig::Memory => write!(f, "memory"),
+            StorageConfig::S3(s3) => write!(
+                f,
+                "s3 (bucket {} at {}, prefix {:?})",
+                s3.bucket,
+                s3.endpoint,
+                s3.prefix.as_deref().unwrap_or("")
+            ),
+            StorageConfig::Sqlite(sqlite) => match &sqlite.path {
+                Some(path) => write!(f, "sqlite ({:?})", path),
+                None => write!(f, "sqlite"),
+            },
+        }
+    }
+}
+
+impl fmt::Display for RateLimitConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limit = |per_minute: Option<u32>| match per_minute {
+            Some(n) if n > 0 => format!("{}/min", n),
+            _ => "unlimited".to_string(),
+        };
+        write!(
+            f,
+            "download {}, upload {}, delete {}",
+            limit(self.download_per_minute),
+            limit(self.upload_per_minute),
+            limit(self.delete_per_minute)
+        )?;
+        if let Some(burst) = self.burst {
+            write!(f, ", burst {}", burst)?;
+        }
+        if self.per_backup_id.unwrap_or(false) {
+            write!(f, ", per backup id")?;
+        }
+        Ok(())
+    }
 }
 
 impl ServerConfig {
+    /// Read the config file.
+    ///
+    /// Unknown settings are rejected, deprecated settings are logged and
+    /// ignored.
     pub fn from_file(config_path: &Path) -> Result<Self, String> {
-        // Read config file
-        if !config_path.exists() {
-            return Err(format!("Config file at {:?} does not exist", config_path));
+        let (config, warnings) = Self::load(Some(config_path), iter::empty(), &[])?;
+        for warning in warnings {
+            warn!("{}", warning);
+        }
+        Ok(config)
+    }
+
+    /// Load the config from an optional config file, environment variables
+    /// and `KEY=VALUE` settings from the command line.
+    ///
+    /// Environment variables with the `SEKURSRANKO_` prefix override the
+    /// config file, the settings override both. Keys in sections are joined
+    /// with `__` in environment variables and with `.` in settings (e.g.
+    /// `SEKURSRANKO_STORAGE__BACKEND` and `storage.backend`). Returns
+    /// warnings about deprecated settings along with the config.
+    pub fn load<I>(
+        config_path: Option<&Path>,
+        env: I,
+        settings: &[String],
+    ) -> Result<(Self, Vec<String>), String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let (table, contents, mut warnings) = read_table(config_path, env, settings)?;
+        let (config, more_warnings, _) = Self::from_table(table, contents.as_deref(), false)
+            .map_err(|e| format!("Could not deserialize config: {}", e))?;
+        warnings.extend(more_warnings);
+        Ok((config, warnings))
+    }
+
+    /// Load the config like [`ServerConfig::load`], but skip unknown settings
+    /// instead of rejecting them.
+    ///
+    /// Returns the unknown settings along with the config and the warnings,
+    /// so that they can be reported together with other problems.
+    pub fn load_lenient<I>(
+        config_path: Option<&Path>,
+        env: I,
+        settings: &[String],
+    ) -> Result<(Self, Vec<String>, Vec<String>), String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let (table, contents, mut warnings) = read_table(config_path, env, settings)?;
+        let (config, more_warnings, unknown) =
+            Self::from_table(table, contents.as_deref(), true)
+                .map_err(|e| format!("Could not deserialize config: {}", e))?;
+        warnings.extend(more_warnings);
+        Ok((config, warnings, unknown))
+    }
+
+    /// Deserialize the config from a table.
+    ///
+    /// If the table was read from `contents` without changes, the contents
+    /// are deserialized instead, for errors with line numbers. If
+    /// `skip_unknown` is set, unknown settings are removed and returned
+    /// instead of being rejected.
+    fn from_table(
+        mut table: toml::Table,
+        contents: Option<&str>,
+        skip_unknown: bool,
+    ) -> Result<(Self, Vec<String>, Vec<String>), String> {
+        let mut warnings = vec![];
+        for (key, hint) in DEPRECATED_SETTINGS {
+            if table.remove(*key).is_some() {
+                warnings.push(format!(
+                    "The setting `{}` is deprecated and ignored, {}",
+                    key, hint
+                ));
+            }
+        }
+        let unknown = if skip_unknown {
+            remove_unknown_settings(&mut table)
+        } else {
+            vec![]
+        };
+        // Serde ignores the fields of unit variants, even with
+        // `deny_unknown_fields`
+        if let Some(toml::Value::Table(storage)) = table.get("storage") {
+            if storage.get("backend").and_then(toml::Value::as_str) == Some("memory") {
+                if let Some(key) = storage.keys().find(|key| *key != "backend") {
+                    return Err(format!(
+                        "unknown field `{}` in `storage`, the memory backend has no settings",
+                        key
+                    ));
+                }
+            }
+        }
+        let config = match contents {
+            Some(contents) if warnings.is_empty() && unknown.is_empty() => toml::from_str(contents),
+            _ => toml::Value::Table(table).try_into(),
+        };
+        config
+            .map(|config| (config, warnings, unknown))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Return the settings that differ in the other config.
+    pub fn changes(&self, other: &Self) -> Vec<Change> {
+        macro_rules! changes {
+            ($($field:ident),*) => {{
+                // Fails to compile if a field is missing
+                let Self { $($field),* } = self;
+                let mut changes = vec![];
+                $(
+                    if *$field != other.$field {
+                        changes.push(Change::new(
+                            stringify!($field),
+                            format!("{:?}", $field),
+                            format!("{:?}", other.$field),
+                        ));
+                    }
+                )*
+                changes
+            }};
+        }
+        changes!(
+            max_backup_bytes,
+            retention_days,
+            backup_dir,
+            listen_on,
+            allow_browser,
+            sweep_interval_secs,
+            storage,
+            tls_cert,
+            tls_key,
+            tls_handshake_timeout_secs,
+            rate_limit,
+            metrics_listen_on,
+            min_free_bytes,
+            shutdown_timeout_secs,
+            orphan_max_age_secs,
+            keep_versions,
+            trash_days,
+            admin_token,
+            encryption_key_file
+        )
+    }
+
+    /// Check the config for problems that deserialization does not catch.
+    ///
+    /// Returns a description of every problem found, so that they can be
+    /// fixed at once. This also opens the storage backend.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.max_backup_bytes == 0 {
+            problems.push("max_backup_bytes must be greater than 0".to_string());
+        }
+        if self.retention_days == 0 {
+            problems.push("retention_days must be greater than 0".to_string());
+        }
+        if self.sweep_interval_secs == Some(0) {
+            problems.push("sweep_interval_secs must be greater than 0".to_string());
+        }
+        if let Err(e) = self.listen_on.parse::<SocketAddr>() {
+            problems.push(format!(
+                "listen_on {:?} is not a valid address: {}",
+                self.listen_on, e
+            ));
+        }
+        if let Some(metrics_listen_on) = &self.metrics_listen_on {
+            if let Err(e) = metrics_listen_on.parse::<SocketAddr>() {
+                problems.push(format!(
+                    "metrics_listen_on {:?} is not a valid address: {}",
+                    metrics_listen_on, e
+                ));
+            }
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                for (setting, path) in [("tls_cert", cert), ("tls_key", key)] {
+                    if !path.is_file() {
+                        problems.push(format!("{} {:?} does not exist", setting, path));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => problems.push("Both tls_cert and tls_key must be set to enable TLS".to_string()),
+        }
+        if self.tls_handshake_timeout_secs == Some(0) {
+            problems.push("tls_handshake_timeout_secs must be greater than 0".to_string());
+        }
+        if self.admin_token.as_deref() == Some("") {
+            problems.push("admin_token must not be empty".to_string());
+        }
+        if let StorageConfig::Filesystem(_) = self.storage.clone().unwrap_or_default() {
+            let check = health::check_backup_dir(&self.backup_dir);
+            if !check.ok {
+                problems.push(format!("backup_dir {}", check.detail));
+            }
+        }
+        if let Err(e) = storage::check_config(self) {
+            problems.push(format!("Invalid storage configuration: {:#}", e));
+        }
+        problems
+    }
+}
+
+/// A changed setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub setting: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+impl Change {
+    fn new(setting: &'static str, old: String, new: String) -> Self {
+        if SECRET_SETTINGS.contains(&setting) {
+            let hidden = "<hidden>".to_string();
+            return Self {
+                setting,
+                old: hidden.clone(),
+                new: hidden,
+            };
         }
-        if !config_path.is_file() {
-            return Err(format!("Config file at {:?} is not a file", config_path));
+        Self { setting, old, new }
+    }
+
+    /// Whether the setting takes effect when the config is reloaded.
+    pub fn is_reloadable(&self) -> bool {
+        RELOADABLE_SETTINGS.contains(&self.setting)
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.setting, self.old, self.new)
+    }
+}
+
+/// A server config that can be replaced while the server is running.
+#[derive(Debug)]
+pub struct ReloadableConfig {
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl ReloadableConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            config: RwLock::new(Arc::new(config)),
         }
-        let mut file =
-            File::open(config_path).map_err(|e| format!("Could not open config file: {}", e))?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .map_err(|e| format!("Could not read config file: {}", e))?;
+    }
+
+    /// Return the current config.
+    pub fn get(&self) -> Arc<ServerConfig> {
+        self.config.read().expect("Config lock is poisoned").clone()
+    }
 
-        // Deserialize
-        toml::from_str(&contents).map_err(|e| format!("Could not deserialize config file: {}", e))
+    /// Replace the config and return the changed settings.
+    ///
+    /// If a setting changed that is not reloadable, the config is kept and
+    /// an error listing these settings is returned.
+    pub fn reload(&self, config: ServerConfig) -> Result<Vec<Change>, String> {
+        let mut current = self.config.write().expect("Config lock is poisoned");
+        let changes = current.changes(&config);
+        let rejected: Vec<_> = changes
+            .iter()
+            .filter(|change| !change.is_reloadable())
+            .map(|change| change.setting)
+            .collect();
+        if !rejected.is_empty() {
+            return Err(format!(
+                "Changing {} requires a restart",
+                rejected.join(", ")
+            ));
+        }
+        *current = Arc::new(config);
+        Ok(changes)
     }
 }
 
+/// Read the config file and apply the overrides from the environment and
+/// the settings.
+///
+/// Returns the contents of the config file too if nothing was overridden,
+/// and warnings about environment variables that are not settings. Unlike
+/// unknown settings, these are ignored, since the environment may contain
+/// unrelated variables with the same prefix.
+fn read_table<I>(
+    config_path: Option<&Path>,
+    env: I,
+    settings: &[String],
+) -> Result<(toml::Table, Option<String>, Vec<String>), String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let contents = match config_path {
+        Some(path) => read_config_file(path)?,
+        None => String::new(),
+    };
+    let mut table: toml::Table = toml::from_str(&contents)
+        .map_err(|e| format!("Could not deserialize config file: {}", e))?;
+
+    let mut env: Vec<_> = env
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && !ENV_RESERVED.contains(&&**name))
+        .collect();
+    env.sort();
+    let overridden = !env.is_empty() || !settings.is_empty();
+    let mut warnings = vec![];
+    for (name, value) in env {
+        let key = name[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+        if !is_setting(&key) {
+            warnings.push(format!(
+                "Ignoring environment variable {}, `{}` is not a setting",
+                name, key
+            ));
+            continue;
+        }
+        set_setting(&mut table, &key, &value)
+            .map_err(|e| format!("Invalid environment variable {}: {}", name, e))?;
+    }
+    for setting in settings {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid setting {:?}, expected KEY=VALUE", setting))?;
+        set_setting(&mut table, key.trim(), value.trim())
+            .map_err(|e| format!("Invalid setting {:?}: {}", setting, e))?;
+    }
+
+    Ok((
+        table,
+        if overridden { None } else { Some(contents) },
+        warnings,
+    ))
+}
+
+/// Return whether a key (e.g. `storage.bucket`) is a setting, deprecated or
+/// not.
+///
+/// Settings in the `storage` section count if any backend knows them.
+fn is_setting(key: &str) -> bool {
+    match key.split_once('.') {
+        None => {
+            field_names::<ServerConfig>().contains(&key)
+                || DEPRECATED_SETTINGS.iter().any(|(name, _)| *name == key)
+        }
+        Some(("rate_limit", name)) => field_names::<RateLimitConfig>().contains(&name),
+        Some(("storage", name)) => {
+            name == "backend"
+                || field_names::<FilesystemConfig>().contains(&name)
+                || field_names::<S3Config>().contains(&name)
+                || field_names::<SqliteConfig>().contains(&name)
+        }
+        Some(_) => false,
+    }
+}
+
+/// Remove the settings that are not known from the table and return them.
+///
+/// The settings in the `storage` section are checked against the selected
+/// backend. Unknown settings are described like the errors of serde (e.g.
+/// "unknown field `storage.shard`").
+fn remove_unknown_settings(table: &mut toml::Table) -> Vec<String> {
+    let mut unknown = remove_unknown(table, "", field_names::<ServerConfig>());
+    if let Some(toml::Value::Table(rate_limit)) = table.get_mut("rate_limit") {
+        unknown.extend(remove_unknown(
+            rate_limit,
+            "rate_limit.",
+            field_names::<RateLimitConfig>(),
+        ));
+    }
+    if let Some(toml::Value::Table(storage)) = table.get_mut("storage") {
+        let fields = match storage.get("backend").and_then(toml::Value::as_str) {
+            Some("filesystem") => Some(field_names::<FilesystemConfig>()),
+            Some("memory") => Some(&[][..]),
+            Some("s3") => Some(field_names::<S3Config>()),
+            Some("sqlite") => Some(field_names::<SqliteConfig>()),
+            // Serde reports missing or unknown backends
+            _ => None,
+        };
+        if let Some(fields) = fields {
+            let backend = storage.remove("backend");
+            unknown.extend(remove_unknown(storage, "storage.", fields));
+            storage.extend(backend.map(|backend| ("backend".to_string(), backend)));
+        }
+    }
+    unknown
+}
+
+/// Remove the keys that are not in `fields` from the table and describe them.
+fn remove_unknown(table: &mut toml::Table, prefix: &str, fields: &[&str]) -> Vec<String> {
+    let keys: Vec<_> = table
+        .keys()
+        .filter(|key| !fields.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    keys.into_iter()
+        .map(|key| {
+            table.remove(&key);
+            format!("unknown field `{}{}`", prefix, key)
+        })
+        .collect()
+}
+
+/// Return the names of the fields of a config struct, as known to serde.
+fn field_names<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    // The deserializer always fails after recording the field names
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// A deserializer that only records the field names of a struct.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> serde::Deserializer<'de> for FieldNames<'a> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(serde::de::Error::custom("expected a struct"))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(serde::de::Error::custom(
+            "only the field names are recorded",
+        ))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+fn read_config_file(config_path: &Path) -> Result<String, String> {
+    if !config_path.exists() {
+        return Err(format!("Config file at {:?} does not exist", config_path));
+    }
+    if !config_path.is_file() {
+        return Err(format!("Config file at {:?} is not a file", config_path));
+    }
+    let mut file =
+        File::open(config_path).map_err(|e| format!("Could not open config file: {}", e))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(|e| format!("Could not read config file: {}", e))?;
+    Ok(contents)
+}
+
+/// Set a setting, given as key with sections joined by `.`.
+///
+/// Values are parsed as TOML. Values that are not valid TOML, like most
+/// strings, are taken literally, as are values of [`STRING_SETTINGS`] that
+/// are not quoted.
+fn set_setting(table: &mut toml::Table, key: &str, value: &str) -> Result<(), String> {
+    let string_setting = STRING_SETTINGS.contains(&key);
+    if key.split('.').any(str::is_empty) {
+        return Err(format!("invalid key {:?}", key));
+    }
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let name = parts.pop().unwrap_or_default();
+    let mut table = table;
+    for part in parts {
+        table = match table
+            .entry(part)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        {
+            toml::Value::Table(section) => section,
+            _ => return Err(format!("`{}` is not a section", part)),
+        };
+    }
+    let value = toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .filter(|parsed| !string_setting || parsed.is_str())
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    table.insert(name.to_string(), value);
+    Ok(())
+}
+
 impl fmt::Display for ServerConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "- Max backup bytes: {}", self.max_backup_bytes)?;
@@ -55,6 +776,68 @@ impl fmt::Display for ServerConfig {
             "- Allow browser access: {}",
             self.allow_browser.unwrap_or(false)
         )?;
+        writeln!(
+            f,
+            "- Sweep interval: {}s",
+            self.sweep_interval_secs.unwrap_or(3600)
+        )?;
+        writeln!(
+            f,
+            "- Storage backend: {}",
+            self.storage.clone().unwrap_or_default()
+        )?;
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => writeln!(
+                f,
+                "- TLS: {:?} / {:?} (handshake timeout {}s)",
+                cert,
+                key,
+                self.tls_handshake_timeout_secs.unwrap_or(10)
+            )?,
+            _ => writeln!(f, "- TLS: disabled")?,
+        }
+        match &self.rate_limit {
+            Some(rate_limit) => writeln!(f, "- Rate limit: {}", rate_limit)?,
+            None => writeln!(f, "- Rate limit: disabled")?,
+        }
+        writeln!(
+            f,
+            "- Metrics listening address: {}",
+            self.metrics_listen_on.as_deref().unwrap_or("disabled")
+        )?;
+        writeln!(
+            f,
+            "- Min free bytes: {}",
+            self.min_free_bytes.unwrap_or(self.max_backup_bytes)
+        )?;
+        writeln!(
+            f,
+            "- Shutdown timeout: {}s",
+            self.shutdown_timeout_secs.unwrap_or(30)
+        )?;
+        writeln!(
+            f,
+            "- Orphaned upload max age: {}s",
+            self.orphan_max_age_secs.unwrap_or(3600)
+        )?;
+        writeln!(f, "- Kept versions: {}", self.keep_versions.unwrap_or(0))?;
+        match self.trash_days {
+            Some(days) if days > 0 => writeln!(f, "- Trash: {} days", days)?,
+            _ => writeln!(f, "- Trash: disabled")?,
+        }
+        writeln!(
+            f,
+            "- Admin API: {}",
+            if self.admin_token.is_some() {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        )?;
+        match &self.encryption_key_file {
+            Some(path) => writeln!(f, "- Encryption at rest: keys from {:?}", path)?,
+            None => writeln!(f, "- Encryption at rest: disabled")?,
+        }
         Ok(())
     }
 }
@@ -128,7 +911,505 @@ mod tests {
                 backup_dir: PathBuf::from("backups"),
                 listen_on: "127.0.0.1:3000".to_string(),
                 allow_browser: Some(true),
+                sweep_interval_secs: None,
+                storage: None,
+                tls_cert: None,
+                tls_key: None,
+                tls_handshake_timeout_secs: None,
+                rate_limit: None,
+                metrics_listen_on: None,
+                min_free_bytes: None,
+                shutdown_timeout_secs: None,
+                orphan_max_age_secs: None,
+                keep_versions: None,
+                trash_days: None,
+                admin_token: None,
+                encryption_key_file: None,
             }
         );
     }
+
+    #[test]
+    fn read_config_file_rate_limit() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[rate_limit]\n").unwrap();
+        file.write_all(b"upload_per_minute = 10\n").unwrap();
+        file.write_all(b"per_backup_id = true\n").unwrap();
+        let res = ServerConfig::from_file(tempfile.path());
+        assert_eq!(
+            res.unwrap().rate_limit,
+            Some(RateLimitConfig {
+                upload_per_minute: Some(10),
+                per_backup_id: Some(true),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn read_config_file_storage() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"memory\"\n").unwrap();
+        let res = ServerConfig::from_file(tempfile.path());
+        assert_eq!(res.unwrap().storage, Some(StorageConfig::Memory));
+    }
+
+    #[test]
+    fn read_config_file_storage_sqlite() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"sqlite\"\n").unwrap();
+        let storage = ServerConfig::from_file(tempfile.path()).unwrap().storage;
+        let sqlite = match storage {
+            Some(StorageConfig::Sqlite(sqlite)) => sqlite,
+            other => panic!("Unexpected storage {:?}", other),
+        };
+        assert_eq!(
+            sqlite.path(Path::new("backups")),
+            PathBuf::from("backups/backups.sqlite3")
+        );
+    }
+
+    #[test]
+    fn read_config_file_storage_s3() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"s3\"\n").unwrap();
+        file.write_all(b"endpoint = \"http://127.0.0.1:9000\"\n")
+            .unwrap();
+        file.write_all(b"bucket = \"safe\"\n").unwrap();
+        file.write_all(b"access_key_id = \"id\"\n").unwrap();
+        file.write_all(b"secret_access_key = \"secret\"\n").unwrap();
+        let res = ServerConfig::from_file(tempfile.path());
+        assert_eq!(
+            res.unwrap().storage,
+            Some(StorageConfig::S3(S3Config {
+                endpoint: "http://127.0.0.1:9000".to_string(),
+                bucket: "safe".to_string(),
+                prefix: None,
+                region: None,
+                access_key_id: "id".to_string(),
+                secret_access_key: "secret".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn read_config_file_unknown_setting() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_day = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        let res = ServerConfig::from_file(tempfile.path());
+        assert!(res.unwrap_err().contains("unknown field `retention_day`"));
+    }
+
+    #[test]
+    fn read_config_file_unknown_storage_setting() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"filesystem\"\n").unwrap();
+        file.write_all(b"shard = true\n").unwrap();
+        let res = ServerConfig::from_file(tempfile.path());
+        assert!(res.unwrap_err().contains("unknown field `shard`"));
+    }
+
+    #[test]
+    fn read_config_file_unknown_memory_setting() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"memory\"\n").unwrap();
+        file.write_all(b"sharded = true\n").unwrap();
+        let res = ServerConfig::from_file(tempfile.path());
+        assert!(res.unwrap_err().contains("unknown field `sharded`"));
+    }
+
+    #[test]
+    fn load_lenient_unknown_settings() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_day = 100\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"io_threads = 4\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[rate_limit]\n").unwrap();
+        file.write_all(b"burst = 5\n").unwrap();
+        file.write_all(b"brust = 5\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"memory\"\n").unwrap();
+        file.write_all(b"sharded = true\n").unwrap();
+        let (config, warnings, unknown) =
+            ServerConfig::load_lenient(Some(tempfile.path()), iter::empty(), &[]).unwrap();
+        assert_eq!(config.retention_days, 100);
+        assert_eq!(config.rate_limit.unwrap().burst, Some(5));
+        assert_eq!(config.storage, Some(StorageConfig::Memory));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            unknown,
+            vec![
+                "unknown field `retention_day`",
+                "unknown field `rate_limit.brust`",
+                "unknown field `storage.sharded`",
+            ]
+        );
+
+        // Settings of other backends are unknown too
+        let settings = [
+            "storage.backend=sqlite".to_string(),
+            "storage.sharded=true".to_string(),
+        ];
+        let (config, _, unknown) =
+            ServerConfig::load_lenient(Some(tempfile.path()), iter::empty(), &settings).unwrap();
+        assert_eq!(
+            config.storage,
+            Some(StorageConfig::Sqlite(SqliteConfig::default()))
+        );
+        assert!(unknown.contains(&"unknown field `storage.sharded`".to_string()));
+
+        // The strict loader rejects them
+        let res = ServerConfig::load(Some(tempfile.path()), iter::empty(), &[]);
+        assert!(res.unwrap_err().contains("unknown field"));
+    }
+
+    #[test]
+    fn read_config_file_deprecated_setting() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"io_threads = 4\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"memory\"\n").unwrap();
+        let (config, warnings) =
+            ServerConfig::load(Some(tempfile.path()), iter::empty(), &[]).unwrap();
+        assert_eq!(config.retention_days, 100);
+        assert_eq!(config.storage, Some(StorageConfig::Memory));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("`io_threads` is deprecated"));
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn load_overrides() {
+        let mut tempfile = NamedTempFile::new().unwrap();
+        let file = tempfile.as_file_mut();
+        file.write_all(b"max_backup_bytes = 10000\n").unwrap();
+        file.write_all(b"retention_days = 100\n").unwrap();
+        file.write_all(b"backup_dir = \"backups\"\n").unwrap();
+        file.write_all(b"listen_on = \"127.0.0.1:3000\"\n").unwrap();
+        file.write_all(b"[storage]\n").unwrap();
+        file.write_all(b"backend = \"filesystem\"\n").unwrap();
+        let vars = env(&[
+            ("SEKURSRANKO_MAX_BACKUP_BYTES", "20000"),
+            ("SEKURSRANKO_RETENTION_DAYS", "200"),
+            ("SEKURSRANKO_LISTEN_ON", "[::]:3000"),
+            ("SEKURSRANKO_ALLOW_BROWSER", "true"),
+            ("SEKURSRANKO_STORAGE__SHARDED", "true"),
+            ("SEKURSRANKO_ADMIN_TOKEN", "1234"),
+            ("SEKURSRANKO_CONFIG", "config.toml"),
+            ("RETENTION_DAYS", "300"),
+        ]);
+        let settings = vec![
+            "retention_days = 400".to_string(),
+            "admin_token=\"1234\"".to_string(),
+            "rate_limit.burst=5".to_string(),
+        ];
+        let (config, warnings) =
+            ServerConfig::load(Some(tempfile.path()), vars, &settings).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(config.max_backup_bytes, 20_000);
+        assert_eq!(config.retention_days, 400);
+        assert_eq!(config.backup_dir, PathBuf::from("backups"));
+        assert_eq!(config.listen_on, "[::]:3000");
+        assert_eq!(config.allow_browser, Some(true));
+        assert_eq!(
+            config.storage,
+            Some(StorageConfig::Filesystem(FilesystemConfig {
+                sharded: Some(true),
+                fsync: None,
+            }))
+        );
+        assert_eq!(config.admin_token.as_deref(), Some("1234"));
+        assert_eq!(config.rate_limit.unwrap().burst, Some(5));
+    }
+
+    #[test]
+    fn load_numeric_strings() {
+        let vars = env(&[
+            ("SEKURSRANKO_MAX_BACKUP_BYTES", "10000"),
+            ("SEKURSRANKO_RETENTION_DAYS", "100"),
+            ("SEKURSRANKO_BACKUP_DIR", "2024"),
+            ("SEKURSRANKO_ADMIN_TOKEN", "1234"),
+        ]);
+        let settings = vec![
+            "listen_on=127.0.0.1:3000".to_string(),
+            "admin_token=0123".to_string(),
+            "storage.backend=s3".to_string(),
+            "storage.endpoint=https://s3.example.com".to_string(),
+            "storage.bucket=42".to_string(),
+            "storage.access_key_id=1e3".to_string(),
+            "storage.secret_access_key=true".to_string(),
+        ];
+        let (config, _) = ServerConfig::load(None, vars, &settings).unwrap();
+        assert_eq!(config.backup_dir, PathBuf::from("2024"));
+        assert_eq!(config.admin_token.as_deref(), Some("0123"));
+        match config.storage {
+            Some(StorageConfig::S3(s3)) => {
+                assert_eq!(s3.bucket, "42");
+                assert_eq!(s3.access_key_id, "1e3");
+                assert_eq!(s3.secret_access_key, "true");
+            }
+            other => panic!("Unexpected storage config {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_without_file() {
+        let vars = env(&[
+            ("SEKURSRANKO_MAX_BACKUP_BYTES", "10000"),
+            ("SEKURSRANKO_RETENTION_DAYS", "100"),
+            ("SEKURSRANKO_BACKUP_DIR", "/var/lib/sekursranko"),
+            ("SEKURSRANKO_STORAGE__BACKEND", "memory"),
+        ]);
+        let settings = vec!["listen_on=127.0.0.1:3000".to_string()];
+        let (config, _) = ServerConfig::load(None, vars.clone(), &settings).unwrap();
+        assert_eq!(config.backup_dir, PathBuf::from("/var/lib/sekursranko"));
+        assert_eq!(config.listen_on, "127.0.0.1:3000");
+        assert_eq!(config.storage, Some(StorageConfig::Memory));
+
+        let res = ServerConfig::load(None, vars, &[]);
+        assert!(res.unwrap_err().contains("missing field `listen_on`"));
+    }
+
+    #[test]
+    fn load_invalid_overrides() {
+        let load = |vars: &[(&str, &str)], settings: &[&str]| {
+            let settings: Vec<String> = settings.iter().map(|s| s.to_string()).collect();
+            ServerConfig::load(None, env(vars), &settings).unwrap_err()
+        };
+        assert_eq!(
+            load(&[], &["retention_days"]),
+            "Invalid setting \"retention_days\", expected KEY=VALUE"
+        );
+        assert_eq!(
+            load(&[], &["storage..backend=memory"]),
+            "Invalid setting \"storage..backend=memory\": invalid key \"storage..backend\""
+        );
+        assert_eq!(
+            load(&[("SEKURSRANKO_LISTEN_ON", "x")], &["listen_on.port=3000"]),
+            "Invalid setting \"listen_on.port=3000\": `listen_on` is not a section"
+        );
+        assert!(load(&[], &["retention=180"]).contains("unknown field `retention`"));
+    }
+
+    #[test]
+    fn load_unknown_env_vars() {
+        let vars = env(&[
+            ("SEKURSRANKO_MAX_BACKUP_BYTES", "10000"),
+            ("SEKURSRANKO_RETENTION_DAYS", "100"),
+            ("SEKURSRANKO_RETENTION", "180"),
+            ("SEKURSRANKO_BACKUP_DIR", "backups"),
+            ("SEKURSRANKO_LISTEN_ON", "127.0.0.1:3000"),
+            ("SEKURSRANKO_LISTEN_ON__PORT", "3000"),
+            ("SEKURSRANKO_IO_THREADS", "4"),
+        ]);
+        let (config, warnings) = ServerConfig::load(None, vars, &[]).unwrap();
+        assert_eq!(config.retention_days, 100);
+        assert_eq!(
+            warnings,
+            vec![
+                "Ignoring environment variable SEKURSRANKO_LISTEN_ON__PORT, `listen_on.port` is not a setting",
+                "Ignoring environment variable SEKURSRANKO_RETENTION, `retention` is not a setting",
+                "The setting `io_threads` is deprecated and ignored, the server sizes its thread pool itself",
+            ]
+        );
+    }
+
+    fn valid_config(backup_dir: &Path) -> ServerConfig {
+        ServerConfig {
+            max_backup_bytes: 10_000,
+            retention_days: 100,
+            backup_dir: backup_dir.to_path_buf(),
+            listen_on: "127.0.0.1:3000".to_string(),
+            allow_browser: None,
+            sweep_interval_secs: None,
+            storage: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_handshake_timeout_secs: None,
+            rate_limit: None,
+            metrics_listen_on: None,
+            min_free_bytes: None,
+            shutdown_timeout_secs: None,
+            orphan_max_age_secs: None,
+            keep_versions: None,
+            trash_days: None,
+            admin_token: None,
+            encryption_key_file: None,
+        }
+    }
+
+    #[test]
+    fn validate_ok() {
+        let backup_dir = tempfile::tempdir().unwrap();
+        assert!(valid_config(backup_dir.path()).validate().is_empty());
+    }
+
+    #[test]
+    fn validate_problems() {
+        let backup_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            max_backup_bytes: 0,
+            retention_days: 0,
+            backup_dir: backup_dir.path().join("missing"),
+            listen_on: "localhost".to_string(),
+            sweep_interval_secs: Some(0),
+            tls_cert: Some(backup_dir.path().join("fullchain.pem")),
+            tls_handshake_timeout_secs: Some(0),
+            admin_token: Some(String::new()),
+            ..valid_config(backup_dir.path())
+        };
+        let problems = config.validate();
+        assert_eq!(problems.len(), 8, "{:?}", problems);
+        assert_eq!(problems[0], "max_backup_bytes must be greater than 0");
+        assert_eq!(problems[1], "retention_days must be greater than 0");
+        assert_eq!(problems[2], "sweep_interval_secs must be greater than 0");
+        assert!(problems[3].starts_with("listen_on \"localhost\" is not a valid address"));
+        assert_eq!(
+            problems[4],
+            "Both tls_cert and tls_key must be set to enable TLS"
+        );
+        assert_eq!(
+            problems[5],
+            "tls_handshake_timeout_secs must be greater than 0"
+        );
+        assert_eq!(problems[6], "admin_token must not be empty");
+        assert!(problems[7].ends_with("missing\" does not exist"));
+    }
+
+    #[test]
+    fn validate_storage() {
+        let backup_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            storage: Some(StorageConfig::Memory),
+            encryption_key_file: Some(backup_dir.path().join("keys")),
+            ..valid_config(backup_dir.path())
+        };
+        assert_eq!(
+            config.validate(),
+            vec!["Invalid storage configuration: The memory backend does not support encryption_key_file"]
+        );
+
+        // The database is not created by validating the config
+        let config = ServerConfig {
+            storage: Some(StorageConfig::Sqlite(SqliteConfig::default())),
+            ..valid_config(backup_dir.path())
+        };
+        assert!(config.validate().is_empty());
+        assert!(!backup_dir.path().join("backups.sqlite3").exists());
+        let config = ServerConfig {
+            storage: Some(StorageConfig::Sqlite(SqliteConfig {
+                path: Some(backup_dir.path().join("missing/backups.sqlite3")),
+            })),
+            ..valid_config(backup_dir.path())
+        };
+        assert_eq!(config.validate().len(), 1);
+    }
+
+    #[test]
+    fn config_changes() {
+        let backup_dir = tempfile::tempdir().unwrap();
+        let config = valid_config(backup_dir.path());
+        assert!(config.changes(&config).is_empty());
+        let other = ServerConfig {
+            max_backup_bytes: 20_000,
+            listen_on: "[::]:3000".to_string(),
+            admin_token: Some("secret".to_string()),
+            ..config.clone()
+        };
+        let changes: Vec<_> = config
+            .changes(&other)
+            .iter()
+            .map(|change| (change.to_string(), change.is_reloadable()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("max_backup_bytes: 10000 -> 20000".to_string(), true),
+                (
+                    "listen_on: \"127.0.0.1:3000\" -> \"[::]:3000\"".to_string(),
+                    false
+                ),
+                ("admin_token: <hidden> -> <hidden>".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn reload_config() {
+        let backup_dir = tempfile::tempdir().unwrap();
+        let config = ReloadableConfig::new(valid_config(backup_dir.path()));
+        let reloaded = ServerConfig {
+            max_backup_bytes: 20_000,
+            allow_browser: Some(true),
+            ..valid_config(backup_dir.path())
+        };
+        let changes = config.reload(reloaded.clone()).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(*config.get(), reloaded);
+
+        let rejected = ServerConfig {
+            max_backup_bytes: 30_000,
+            listen_on: "[::]:3000".to_string(),
+            retention_days: 200,
+            ..reloaded.clone()
+        };
+        assert_eq!(
+            config.reload(rejected),
+            Err("Changing retention_days, listen_on requires a restart".to_string())
+        );
+        assert_eq!(*config.get(), reloaded);
+    }
 }
@@ -1,15 +1,19 @@
-use std::{io::Error as IoError, os::unix::fs::PermissionsExt, path::Path};
+use std::{net::SocketAddr, time::Duration};
 
-use anyhow::{bail, Context};
-use futures::StreamExt;
-use hyper::{header, Body, Method, Request, Response, StatusCode};
-use log::{debug, error, info, trace, warn};
-use rand::Rng;
-use tokio::{fs, io::AsyncWriteExt};
+use hyper::{header, Body, HeaderMap, Method, Request, Response, StatusCode};
+use log::{debug, error, info, warn};
 
 use crate::{
+    admin,
+    conditional::{self, quote_etag},
     config::{ServerConfig, ServerConfigPublic},
-    routing::{Route, Router},
+    health,
+    metrics::metrics,
+    range::{self, ByteRange},
+    rate_limit::RequestClass,
+    routing::Route,
+    service::State,
+    storage::{BackupMetadata, BackupStore},
 };
 
 macro_rules! require_accept_starts_with {
@@ -57,13 +61,23 @@ macro_rules! require_content_type_is {
 }
 
 /// Main handler.
-pub async fn handler(
+pub(crate) async fn handler(
     req: Request<Body>,
-    router: &Router,
-    config: &ServerConfig,
+    state: &State,
+    remote_addr: Option<SocketAddr>,
 ) -> Result<Response<Body>, hyper::Error> {
+    let config = state.config.get();
+    let route_match = state.router.recognize(req.uri().path()).ok();
+
+    // Health checks are done by the orchestrator and admin requests by
+    // operators, not by the app
+    let is_app_request = !route_match.as_ref().is_some_and(|m| {
+        let route = **m.handler();
+        route == Route::Healthz || route == Route::Readyz || route.is_admin()
+    });
+
     // Verify headers
-    if !config.allow_browser.unwrap_or(false) {
+    if !config.allow_browser.unwrap_or(false) && is_app_request {
         match req
             .headers()
             .get(header::USER_AGENT)
@@ -80,7 +94,7 @@ pub async fn handler(
         }
     }
 
-    let mut response = if let Ok(route_match) = router.recognize(req.uri().path()) {
+    let mut response = if let Some(route_match) = route_match {
         match route_match.handler() {
             Route::Index => {
                 if req.method() == Method::GET {
@@ -91,46 +105,40 @@ pub async fn handler(
             }
             Route::Config => {
                 if req.method() == Method::GET {
-                    handle_config(&req, config)
+                    handle_config(&req, &config)
                 } else {
                     response_405_method_not_allowed()
                 }
             }
-            Route::Backup => match *req.method() {
-                Method::GET | Method::HEAD => {
-                    handle_get_backup(
-                        &req,
-                        config,
-                        route_match
-                            .params()
-                            .find("backupId")
-                            .expect("Missing backupId param"),
-                    )
-                    .await
-                }
-                Method::PUT => {
-                    handle_put_backup(
-                        req,
-                        config,
-                        route_match
-                            .params()
-                            .find("backupId")
-                            .expect("Missing backupId param"),
-                    )
-                    .await
+            Route::Backup => {
+                let backup_id = route_match
+                    .params()
+                    .find("backupId")
+                    .expect("Missing backupId param");
+                handle_backup(req, state, &config, remote_addr, backup_id).await
+            }
+            Route::Healthz => {
+                if req.method() == Method::GET {
+                    handle_healthz()
+                } else {
+                    response_405_method_not_allowed()
                 }
-                Method::DELETE => {
-                    handle_delete_backup(
-                        config,
-                        route_match
-                            .params()
-                            .find("backupId")
-                            .expect("Missing backupId param"),
-                    )
-                    .await
+            }
+            Route::Readyz => {
+                if req.method() == Method::GET {
+                    handle_readyz(state).await
+                } else {
+                    response_405_method_not_allowed()
                 }
-                _ => response_405_method_not_allowed(),
-            },
+            }
+            Route::AdminVersions
+            | Route::AdminRestore
+            | Route::AdminTrash
+            | Route::AdminUntrash
+            | Route::AdminStats
+            | Route::AdminReadiness => {
+                admin::handle(&req, state, **route_match.handler(), route_match.params()).await
+            }
         }
     } else {
         response_404_not_found()
@@ -147,6 +155,62 @@ pub async fn handler(
     Ok(response)
 }
 
+async fn handle_backup(
+    req: Request<Body>,
+    state: &State,
+    config: &ServerConfig,
+    remote_addr: Option<SocketAddr>,
+    backup_id: &str,
+) -> Response<Body> {
+    let class = match RequestClass::from_method(req.method()) {
+        Some(class) => class,
+        None => return response_405_method_not_allowed(),
+    };
+
+    // Validate params before creating rate limiter buckets or locks for them
+    if !backup_id_valid(backup_id) {
+        warn!(
+            "{:?} request for backup with invalid id: {}",
+            class, backup_id
+        );
+        return match class {
+            RequestClass::Download => response_404_not_found(),
+            RequestClass::Upload | RequestClass::Delete => {
+                response_400_bad_request("{\"detail\": \"Invalid backup ID\"}")
+            }
+        };
+    }
+
+    // Throttle before touching the store, to slow down enumeration of backup ids
+    let ip = remote_addr.map(|addr| addr.ip());
+    if let Err(wait) = state.rate_limiter.check(class, ip, backup_id) {
+        warn!(
+            "Rate limit for {:?} requests exceeded by {:?}",
+            class, remote_addr
+        );
+        return response_429_too_many_requests(wait);
+    }
+
+    // Uploads and deletions of the same backup must not overlap, downloads
+    // wait until they are finished
+    let _guard = match class {
+        RequestClass::Download => state.locks.read(backup_id).await,
+        RequestClass::Upload | RequestClass::Delete => match state.locks.try_write(backup_id) {
+            Some(guard) => guard,
+            None => {
+                warn!("Backup {} is already being modified", backup_id);
+                return response_409_conflict();
+            }
+        },
+    };
+
+    match class {
+        RequestClass::Download => handle_get_backup(&req, &*state.store, backup_id).await,
+        RequestClass::Upload => handle_put_backup(req, config, &*state.store, backup_id).await,
+        RequestClass::Delete => handle_delete_backup(&req, config, &*state.store, backup_id).await,
+    }
+}
+
 fn handle_index() -> Response<Body> {
     Response::builder()
         .status(StatusCode::OK)
@@ -169,10 +233,42 @@ fn handle_config(req: &Request<Body>, config: &ServerConfig) -> Response<Body> {
         .expect("Could not create response")
 }
 
+fn handle_healthz() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from("{\"status\": \"ok\"}"))
+        .expect("Could not create response")
+}
+
+/// Return whether the server is ready.
+///
+/// The details of the checks are only available through the admin API, since
+/// they reveal paths and disk usage.
+async fn handle_readyz(state: &State) -> Response<Body> {
+    let readiness = health::readiness(state).await;
+    if !readiness.ready {
+        warn!("Readiness check failed: {:?}", readiness.checks);
+    }
+    let (status, body) = if readiness.ready {
+        (StatusCode::OK, "{\"status\": \"ok\"}")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "{\"status\": \"unavailable\"}",
+        )
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .expect("Could not create response")
+}
+
 /// Return whether this backup id is valid.
 ///
 /// A backup id must be a 64 character lowercase hex string.
-fn backup_id_valid(backup_id: &str) -> bool {
+pub fn backup_id_valid(backup_id: &str) -> bool {
     backup_id.len() == 64
         && backup_id
             .chars()
@@ -181,82 +277,184 @@ fn backup_id_valid(backup_id: &str) -> bool {
 
 async fn handle_get_backup(
     req: &Request<Body>,
-    config: &ServerConfig,
+    store: &dyn BackupStore,
     backup_id: &str,
 ) -> Response<Body> {
     // Validate headers
     require_accept_is!(req, "application/octet-stream");
 
-    // Validate params
-    if !backup_id_valid(backup_id) {
-        warn!(
-            "Download of backup with invalid id was requested: {}",
-            backup_id
-        );
-        return response_404_not_found();
-    }
+    // Ranges are only supported for downloads. If a range is requested, the
+    // backup is only read once it is known which part is needed.
+    let range_header = match req.method() {
+        &Method::GET => req
+            .headers()
+            .get(header::RANGE)
+            .and_then(|v| v.to_str().ok()),
+        _ => None,
+    };
 
-    let is_head_request = req.method() == Method::HEAD;
+    // Both GET and HEAD responses carry the metadata of the backup. If a
+    // condition has to be checked, the backup is only read once it is known
+    // that it must be sent.
+    let conditional = needs_etag(req.headers());
+    let (metadata, body) = if req.method() == Method::HEAD || range_header.is_some() || conditional
+    {
+        match stat(store, backup_id, conditional).await {
+            Ok(Some(metadata)) => (metadata, None),
+            Ok(None) => return response_404_not_found(),
+            Err(e) => {
+                error!("Could not look up backup: {:#}", e);
+                return response_500_internal_server_error();
+            }
+        }
+    } else {
+        match store.get(backup_id).await {
+            Ok(Some(backup)) => (backup.metadata, Some(backup.body)),
+            Ok(None) => return response_404_not_found(),
+            Err(e) => {
+                error!("Could not read backup: {:#}", e);
+                return response_500_internal_server_error();
+            }
+        }
+    };
+
+    let mut response = Response::builder()
+        .header(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(metadata.modified),
+        )
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(etag) = &metadata.etag {
+        response = response.header(header::ETAG, quote_etag(etag));
+    }
+    if let Some(status) = conditional::evaluate(req.headers(), req.method(), Some(&metadata)) {
+        return response
+            .status(status)
+            .body(Body::empty())
+            .expect("Could not create response");
+    }
 
-    let backup_path = config.backup_dir.join(backup_id);
-    if backup_path.exists() && backup_path.is_file() {
-        let body: Body = if is_head_request {
-            Body::empty()
-        } else {
-            let bytes = match fs::read(backup_path).await {
-                Ok(bytes) => bytes,
+    let byte_range = range_header
+        .filter(|_| conditional::if_range_matches(req.headers(), &metadata))
+        .and_then(|value| range::parse(value, metadata.size));
+    let (status, range, body) = match byte_range {
+        Some(ByteRange::Unsatisfiable) => {
+            return response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", metadata.size))
+                .body(Body::empty())
+                .expect("Could not create response");
+        }
+        Some(ByteRange::Satisfiable(range)) => {
+            match store.get_range(backup_id, range.clone()).await {
+                Ok(Some(backup)) => (StatusCode::PARTIAL_CONTENT, range, backup.body),
+                Ok(None) => return response_404_not_found(),
                 Err(e) => {
-                    error!("Could not read file: {}", e);
+                    error!("Could not read backup: {:#}", e);
                     return response_500_internal_server_error();
                 }
+            }
+        }
+        None => {
+            let body = match body {
+                Some(body) => body,
+                None if req.method() == Method::HEAD => Body::empty(),
+                // The conditions are met or the range header was ignored, send
+                // the whole backup
+                None => match store.get(backup_id).await {
+                    Ok(Some(backup)) => backup.body,
+                    Ok(None) => return response_404_not_found(),
+                    Err(e) => {
+                        error!("Could not read backup: {:#}", e);
+                        return response_500_internal_server_error();
+                    }
+                },
             };
-            bytes.into()
-        };
-        Response::builder()
-            .status(StatusCode::OK)
-            .body(body)
-            .expect("Could not create response")
-    } else {
-        response_404_not_found()
+            (StatusCode::OK, 0..metadata.size, body)
+        }
+    };
+
+    if req.method() == Method::GET {
+        metrics().download_bytes.add(range.end - range.start);
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            header::CONTENT_RANGE,
+            range::content_range(&range, metadata.size),
+        );
     }
+    response
+        .status(status)
+        .header(header::CONTENT_LENGTH, range.end - range.start)
+        .body(body)
+        .expect("Could not create response")
 }
 
-// Create a file with permissions set to 0600.
-async fn create_file(path: &Path) -> Result<fs::File, IoError> {
-    let file = fs::File::create(path).await?;
-    let mut perms = file.metadata().await?.permissions();
-    perms.set_mode(0o600);
-    file.set_permissions(perms).await?;
-    Ok(file)
+/// Return whether a conditional header of the request refers to the ETag.
+fn needs_etag(headers: &HeaderMap) -> bool {
+    [header::IF_MATCH, header::IF_NONE_MATCH, header::IF_RANGE]
+        .iter()
+        .any(|name| headers.contains_key(name))
+}
+
+/// Return the metadata of a backup, including the ETag if requested.
+///
+/// Stores may omit the ETag in `stat` if it is expensive to determine, so it
+/// is only looked up separately when a condition needs it.
+async fn stat(
+    store: &dyn BackupStore,
+    backup_id: &str,
+    with_etag: bool,
+) -> anyhow::Result<Option<BackupMetadata>> {
+    let mut metadata = match store.stat(backup_id).await? {
+        Some(metadata) => metadata,
+        None => return Ok(None),
+    };
+    if with_etag && metadata.etag.is_none() {
+        metadata.etag = store.etag(backup_id).await?;
+    }
+    Ok(Some(metadata))
+}
+
+/// Check the `If-Match` and `If-None-Match` headers of a request that
+/// modifies a backup.
+///
+/// Return a response if a precondition is not met.
+async fn check_preconditions(
+    req: &Request<Body>,
+    store: &dyn BackupStore,
+    backup_id: &str,
+) -> Option<Response<Body>> {
+    let headers = req.headers();
+    if !headers.contains_key(header::IF_MATCH) && !headers.contains_key(header::IF_NONE_MATCH) {
+        return None;
+    }
+    let current = match stat(store, backup_id, true).await {
+        Ok(current) => current,
+        Err(e) => {
+            error!("Could not look up backup: {:#}", e);
+            return Some(response_500_internal_server_error());
+        }
+    };
+    let status = conditional::evaluate(headers, req.method(), current.as_ref())?;
+    debug!("Precondition failed for backup {}", backup_id);
+    Some(
+        Response::builder()
+            .status(status)
+            .body(Body::from("{\"detail\": \"Precondition failed\"}"))
+            .expect("Could not create response"),
+    )
 }
 
 async fn handle_put_backup(
     req: Request<Body>,
     config: &ServerConfig,
+    store: &dyn BackupStore,
     backup_id: &str,
 ) -> Response<Body> {
     // Validate headers
     require_content_type_is!(req, "application/octet-stream");
 
-    // Validate params
-    if !backup_id_valid(backup_id) {
-        warn!(
-            "Upload of backup with invalid id was requested: {}",
-            backup_id
-        );
-        return response_400_bad_request("{\"detail\": \"Invalid backup ID\"}");
-    }
-
-    // Validate backup path
-    let backup_path = config.backup_dir.join(backup_id);
-    if backup_path.exists() && !backup_path.is_file() {
-        warn!(
-            "Tried to upload to a backup path that exists but is not a file: {:?}",
-            backup_path
-        );
-        return response_500_internal_server_error();
-    }
-
     // Get Content-Length header
     // We can trust that the actual body size will not be larger than the
     // declared content length, because hyper will actually stop consuming data
@@ -287,117 +485,73 @@ async fn handle_put_backup(
         );
     };
 
+    if let Some(response) = check_preconditions(&req, store, backup_id).await {
+        return response;
+    }
+
     // Write backup
-    match write_backup(req.into_body(), backup_id, &backup_path).await {
+    match store.put(backup_id, req.into_body()).await {
         Ok(updated) => {
+            metrics().upload_bytes.add(content_length.unwrap_or(0));
             info!(
                 "{} backup {}",
                 if updated { "Updated" } else { "Created" },
                 backup_id
             );
-            Response::builder()
-                .status(if updated {
-                    StatusCode::NO_CONTENT
-                } else {
-                    StatusCode::CREATED
-                })
+            let mut response = Response::builder().status(if updated {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::CREATED
+            });
+            // Allow the client to make further updates conditional on this version
+            match store.stat(backup_id).await {
+                Ok(Some(BackupMetadata {
+                    etag: Some(etag), ..
+                })) => response = response.header(header::ETAG, quote_etag(&etag)),
+                Ok(_) => {}
+                Err(e) => warn!("Could not look up uploaded backup: {:#}", e),
+            }
+            response
                 .body(Body::empty())
                 .expect("Could not create response")
         }
         Err(e) => {
-            error!("Could not write backup: {}", e);
+            error!("Could not write backup: {:#}", e);
             response_500_internal_server_error()
         }
     }
 }
 
-/// Store the backup to the file system.
-///
-/// Return true if an existing backup was updated, or false if a new backup was created.
-async fn write_backup(mut body: Body, backup_id: &str, backup_path: &Path) -> anyhow::Result<bool> {
-    // The incoming stream will be written to a temporary file. This is done to prevent
-    // incomplete backups from being persisted.
-    let random_ext: String = {
-        let mut rng = rand::thread_rng();
-        std::iter::repeat(())
-            .map(|_| rng.sample(rand::distributions::Alphanumeric))
-            .map(char::from)
-            .take(10)
-            .collect()
-    };
-    let backup_path_dl = backup_path.with_extension(random_ext);
-    trace!("Writing temporary upload to {:?}", backup_path_dl);
-    if backup_path_dl.exists() {
-        bail!(
-            "Random upload path \"{:?}\" already exists!",
-            backup_path_dl
-        );
-    }
-
-    // Create the empty download file to ensure correct permissions before
-    // writing the data
-    let mut backup_file_dl = create_file(&backup_path_dl)
-        .await
-        .context("Could not create temporary file")?;
-
-    // Write data to temporary file
-    while let Some(chunk_or_error) = body.next().await {
-        let chunk = chunk_or_error.context("Could not read body chunk")?;
-        backup_file_dl
-            .write_all(&chunk)
-            .await
-            .context("Could not write chunk to temporary file")?
-    }
-    trace!("Wrote temp backup for {}", backup_id);
-
-    // Move temporary file to final location
-    let updated = backup_path.exists() && backup_path.is_file();
-    fs::rename(&backup_path_dl, &backup_path)
-        .await
-        .context("Could not move temporary backup to final location")?;
-    trace!("Renamed: {:?} -> {:?}", backup_path_dl, backup_path);
-
-    Ok(updated)
-}
-
-async fn handle_delete_backup(config: &ServerConfig, backup_id: &str) -> Response<Body> {
-    // Validate params
-    if !backup_id_valid(backup_id) {
-        warn!(
-            "Deletion of backup with invalid id was requested: {}",
-            backup_id
-        );
-        return response_400_bad_request("{\"detail\": \"Invalid backup ID\"}");
-    }
-
-    let backup_path = config.backup_dir.join(backup_id);
-
-    // Ensure backup exists
-    if !backup_path.exists() {
-        debug!(
-            "Tried to delete a backup path that does not exist: {:?}",
-            backup_path
-        );
-        return response_404_not_found();
-    }
-
-    // Ensure backup is a file
-    if !backup_path.is_file() {
-        warn!(
-            "Tried to delete a backup path that exists but is not a file: {:?}",
-            backup_path
-        );
-        return response_500_internal_server_error();
+async fn handle_delete_backup(
+    req: &Request<Body>,
+    config: &ServerConfig,
+    store: &dyn BackupStore,
+    backup_id: &str,
+) -> Response<Body> {
+    if let Some(response) = check_preconditions(req, store, backup_id).await {
+        return response;
     }
 
-    // Delete file
-    match fs::remove_file(&backup_path).await {
-        Ok(_) => Response::builder()
+    // With the trash enabled, backups can be restored until they are purged
+    let deleted = if config.trash_days.unwrap_or(0) > 0 {
+        store.trash(backup_id).await
+    } else {
+        store.delete(backup_id).await
+    };
+    match deleted {
+        Ok(true) => Response::builder()
             .status(StatusCode::NO_CONTENT)
             .body(Body::empty())
             .expect("Could not create response"),
+        Ok(false) => {
+            debug!(
+                "Tried to delete a backup that does not exist: {}",
+                backup_id
+            );
+            response_404_not_found()
+        }
         Err(e) => {
-            error!("Could not delete backup at {:?}: {}", &backup_path, e);
+            error!("Could not delete backup {}: {:#}", backup_id, e);
             response_500_internal_server_error()
         }
     }
@@ -410,21 +564,40 @@ fn response_400_bad_request(body: &'static str) -> Response<Body> {
         .expect("Could not create response")
 }
 
-fn response_404_not_found() -> Response<Body> {
+pub(crate) fn response_404_not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
         .body(Body::empty())
         .expect("Could not create response")
 }
 
-fn response_405_method_not_allowed() -> Response<Body> {
+pub(crate) fn response_405_method_not_allowed() -> Response<Body> {
     Response::builder()
         .status(StatusCode::METHOD_NOT_ALLOWED)
         .body(Body::empty())
         .expect("Could not create response")
 }
 
-fn response_500_internal_server_error() -> Response<Body> {
+pub(crate) fn response_409_conflict() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::CONFLICT)
+        .body(Body::from(
+            "{\"detail\": \"Backup is being modified by another request\"}",
+        ))
+        .expect("Could not create response")
+}
+
+fn response_429_too_many_requests(retry_after: Duration) -> Response<Body> {
+    // Round up, so that the request is allowed after waiting
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::RETRY_AFTER, secs)
+        .body(Body::from("{\"detail\": \"Too many requests\"}"))
+        .expect("Could not create response")
+}
+
+pub(crate) fn response_500_internal_server_error() -> Response<Body> {
     Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
         .body(Body::from("{\"detail\": \"Internal server error\"}"))
@@ -435,6 +608,64 @@ fn response_500_internal_server_error() -> Response<Body> {
 mod tests {
     use super::*;
 
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        config::{RateLimitConfig, ReloadableConfig},
+        locks::BackupLocks,
+        rate_limit::RateLimiter,
+        routing::make_router,
+        storage::MemoryStore,
+    };
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            max_backup_bytes: 16,
+            retention_days: 180,
+            backup_dir: PathBuf::from("/nonexistent"),
+            listen_on: "-unittest-".to_string(),
+            allow_browser: None,
+            sweep_interval_secs: None,
+            storage: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_handshake_timeout_secs: None,
+            rate_limit: None,
+            metrics_listen_on: None,
+            min_free_bytes: None,
+            shutdown_timeout_secs: None,
+            orphan_max_age_secs: None,
+            keep_versions: None,
+            trash_days: None,
+            admin_token: None,
+            encryption_key_file: None,
+        }
+    }
+
+    fn test_state(config: ServerConfig) -> State {
+        State {
+            rate_limiter: RateLimiter::new(&config.rate_limit.clone().unwrap_or_default()),
+            config: Arc::new(ReloadableConfig::new(config)),
+            router: make_router(),
+            store: Arc::new(MemoryStore::new()),
+            locks: Arc::new(BackupLocks::new()),
+        }
+    }
+
+    fn backup_request(method: Method, body: &'static [u8]) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(format!("/backups/{}", BACKUP_ID))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
     #[test]
     fn test_is_backup_id_valid() {
         assert!(!backup_id_valid(""));
@@ -447,4 +678,83 @@ mod tests {
             "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
         ));
     }
+
+    #[tokio::test]
+    async fn test_backup_lifecycle_memory_store() {
+        let state = test_state(test_config());
+        let call = |req| handler(req, &state, None);
+
+        let res = call(backup_request(Method::GET, b"")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = call(backup_request(Method::PUT, b"sekurkopio"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = call(backup_request(Method::PUT, b"sekura")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = call(backup_request(Method::PUT, b"tro granda sekurkopio"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = call(backup_request(Method::GET, b"")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"sekura");
+
+        let res = call(backup_request(Method::DELETE, b"")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = call(backup_request(Method::DELETE, b"")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let state = test_state(ServerConfig {
+            rate_limit: Some(RateLimitConfig {
+                upload_per_minute: Some(2),
+                burst: Some(1),
+                ..Default::default()
+            }),
+            ..test_config()
+        });
+        let remote_addr = Some("192.0.2.1:1234".parse().unwrap());
+        let call = |req| handler(req, &state, remote_addr);
+
+        let res = call(backup_request(Method::PUT, b"sekurkopio"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = call(backup_request(Method::PUT, b"sekurkopio"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = res.headers().get(header::RETRY_AFTER).unwrap();
+        assert!(["29", "30"].contains(&retry_after.to_str().unwrap()));
+
+        // Downloads are not limited
+        let res = call(backup_request(Method::GET, b"")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_modification() {
+        let state = test_state(test_config());
+        let call = |req| handler(req, &state, None);
+
+        // Another request is modifying the backup
+        let guard = state.locks.try_write(BACKUP_ID).unwrap();
+        for method in [Method::PUT, Method::DELETE] {
+            let res = call(backup_request(method, b"sekurkopio")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+        }
+        drop(guard);
+
+        let res = call(backup_request(Method::PUT, b"sekurkopio"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert!(state.locks.is_empty());
+    }
 }
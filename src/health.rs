@@ -0,0 +1,137 @@
+//! Readiness checks.
+
+use std::{
+    collections::BTreeMap, ffi::CString, io::Error as IoError, os::unix::ffi::OsStrExt, path::Path,
+};
+
+use serde_derive::Serialize;
+
+use crate::{config::StorageConfig, service::State};
+
+/// A backup id that is only used to check whether the storage backend is reachable.
+const PROBE_BACKUP_ID: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The result of a single readiness check.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn ok(detail: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            detail: detail.into(),
+        }
+    }
+
+    fn failed(detail: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The results of all readiness checks.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Run all readiness checks.
+///
+/// The backup directory checks are only done for the filesystem backend.
+pub(crate) async fn readiness(state: &State) -> Readiness {
+    let config = state.config.get();
+    let mut checks = BTreeMap::new();
+    if let StorageConfig::Filesystem(_) = config.storage.clone().unwrap_or_default() {
+        let dir = &config.backup_dir;
+        checks.insert("backup_dir", check_backup_dir(dir));
+        let min_free_bytes = config.min_free_bytes.unwrap_or(config.max_backup_bytes);
+        checks.insert("free_space", check_free_space(dir, min_free_bytes));
+    }
+    checks.insert(
+        "storage",
+        match state.store.head(PROBE_BACKUP_ID).await {
+            Ok(_) => Check::ok("reachable"),
+            Err(e) => Check::failed(format!("{:#}", e)),
+        },
+    );
+    Readiness {
+        ready: checks.values().all(|check| check.ok),
+        checks,
+    }
+}
+
+pub(crate) fn check_backup_dir(dir: &Path) -> Check {
+    if !dir.is_dir() {
+        return Check::failed(format!("{:?} does not exist", dir));
+    }
+    match access(dir, libc::W_OK) {
+        Ok(()) => Check::ok(format!("{:?} is writable", dir)),
+        Err(e) => Check::failed(format!("{:?} is not writable: {}", dir, e)),
+    }
+}
+
+fn check_free_space(dir: &Path, min_free_bytes: u64) -> Check {
+    match free_space(dir) {
+        Ok(free) if free >= min_free_bytes => Check::ok(format!("{} bytes free", free)),
+        Ok(free) => Check::failed(format!(
+            "{} bytes free, at least {} required",
+            free, min_free_bytes
+        )),
+        Err(e) => Check::failed(format!("Could not determine free space: {}", e)),
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString, IoError> {
+    CString::new(path.as_os_str().as_bytes()).map_err(IoError::from)
+}
+
+fn access(path: &Path, mode: libc::c_int) -> Result<(), IoError> {
+    let path = c_path(path)?;
+    // SAFETY: The path is a valid NUL terminated string.
+    if unsafe { libc::access(path.as_ptr(), mode) } != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+/// Return the number of bytes available to unprivileged users on the
+/// filesystem containing the path.
+fn free_space(path: &Path) -> Result<u64, IoError> {
+    let path = c_path(path)?;
+    // SAFETY: The path is a valid NUL terminated string and `stat` is valid
+    // for writing a `statvfs` struct.
+    let stat = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return Err(IoError::last_os_error());
+        }
+        stat
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_backup_dir(dir.path()).ok);
+        assert!(!check_backup_dir(&dir.path().join("missing")).ok);
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_free_space(dir.path(), 0).ok);
+        assert!(!check_free_space(dir.path(), u64::MAX).ok);
+    }
+}
@@ -1,13 +1,31 @@
 #![deny(clippy::all)]
 
+mod admin;
+mod conditional;
 mod config;
 mod handlers;
+mod health;
+mod locks;
+pub mod metrics;
+mod range;
+mod rate_limit;
 mod routing;
 mod service;
+mod stats;
+pub mod storage;
+mod sweeper;
+pub mod tls;
 
 pub use crate::{
-    config::{ServerConfig, ServerConfigPublic},
+    config::{
+        Change, FilesystemConfig, RateLimitConfig, ReloadableConfig, S3Config, ServerConfig,
+        ServerConfigPublic, SqliteConfig, StorageConfig,
+    },
+    handlers::backup_id_valid,
+    locks::{BackupGuard, BackupLocks},
     service::{BackupService, MakeBackupService},
+    stats::Stats,
+    sweeper::{Clock, Sweeper, SystemClock},
 };
 
 pub static NAME: &str = "Sekurŝranko";
@@ -0,0 +1,159 @@
+//! Locks that serialize access to a single backup.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// Locks per backup id.
+///
+/// Uploads and deletions need exclusive access to a backup, so that their
+/// result (e.g. whether a backup was created or updated) is accurate and
+/// conditional requests are evaluated against the state they modify.
+/// Downloads need shared access, so that they never see a backup change
+/// between reading its metadata and its contents.
+///
+/// The locks only work within a single process. Entries are removed once a
+/// lock is no longer held or awaited.
+#[derive(Debug, Default)]
+pub struct BackupLocks {
+    locks: Mutex<HashMap<String, Arc<RwLock<()>>>>,
+}
+
+/// The guards are never read, only held until they are dropped.
+#[allow(dead_code)]
+#[derive(Debug)]
+enum Guard {
+    Read(OwnedRwLockReadGuard<()>),
+    Write(OwnedRwLockWriteGuard<()>),
+}
+
+/// A held lock for a backup id, released when dropped.
+#[derive(Debug)]
+pub struct BackupGuard<'a> {
+    locks: &'a BackupLocks,
+    backup_id: String,
+    guard: Option<Guard>,
+}
+
+impl BackupLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self, backup_id: &str) -> Arc<RwLock<()>> {
+        self.locks
+            .lock()
+            .expect("Backup locks are poisoned")
+            .entry(backup_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn guard(&self, backup_id: &str, guard: Guard) -> BackupGuard<'_> {
+        BackupGuard {
+            locks: self,
+            backup_id: backup_id.to_string(),
+            guard: Some(guard),
+        }
+    }
+
+    /// Acquire shared access to a backup, waiting for uploads and deletions
+    /// that are in progress.
+    pub async fn read(&self, backup_id: &str) -> BackupGuard<'_> {
+        let guard = self.lock(backup_id).read_owned().await;
+        self.guard(backup_id, Guard::Read(guard))
+    }
+
+    /// Acquire exclusive access to a backup, waiting for other requests that
+    /// are in progress.
+    pub async fn write(&self, backup_id: &str) -> BackupGuard<'_> {
+        let guard = self.lock(backup_id).write_owned().await;
+        self.guard(backup_id, Guard::Write(guard))
+    }
+
+    /// Acquire exclusive access to a backup, or return `None` if the backup
+    /// is in use.
+    pub fn try_write(&self, backup_id: &str) -> Option<BackupGuard<'_>> {
+        match self.lock(backup_id).try_write_owned() {
+            Ok(guard) => Some(self.guard(backup_id, Guard::Write(guard))),
+            Err(_) => {
+                // Drop the entry again if it was only created for this attempt
+                self.remove_unused(backup_id);
+                None
+            }
+        }
+    }
+
+    /// Return the number of backup ids with a lock entry.
+    pub fn len(&self) -> usize {
+        self.locks.lock().expect("Backup locks are poisoned").len()
+    }
+
+    /// Return whether no backup is locked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove_unused(&self, backup_id: &str) {
+        let mut locks = self.locks.lock().expect("Backup locks are poisoned");
+        // Other guards and waiters hold a reference to the lock as well
+        if locks
+            .get(backup_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(backup_id);
+        }
+    }
+}
+
+impl Drop for BackupGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        self.locks.remove_unused(&self.backup_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_backup_locks() {
+        let locks = BackupLocks::new();
+
+        let write = locks.try_write("a").unwrap();
+        assert!(locks.try_write("a").is_none());
+        assert!(locks.try_write("b").is_some());
+        drop(write);
+        assert!(locks.is_empty());
+
+        let read1 = locks.read("a").await;
+        let read2 = locks.read("a").await;
+        assert!(locks.try_write("a").is_none());
+        drop(read1);
+        assert!(locks.try_write("a").is_none());
+        drop(read2);
+        assert!(locks.try_write("a").is_some());
+        assert!(locks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backup_locks_wait() {
+        let locks = Arc::new(BackupLocks::new());
+        let write = locks.write("a").await;
+        let reader = {
+            let locks = locks.clone();
+            tokio::spawn(async move {
+                let _read = locks.read("a").await;
+            })
+        };
+        tokio::task::yield_now().await;
+        assert!(!reader.is_finished());
+        drop(write);
+        reader.await.unwrap();
+        assert!(locks.is_empty());
+    }
+}
@@ -1,17 +1,100 @@
-use std::path::PathBuf;
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use clap::{self, Parser};
-use hyper::Server;
-use log::error;
+use clap::{self, Parser, Subcommand};
+use hyper::{rt::Executor, Server};
+use log::{error, info, warn};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+    task::JoinSet,
+};
 
-use sekursranko::{MakeBackupService, ServerConfig};
+use sekursranko::{
+    backup_id_valid, metrics,
+    storage::{self, BackupStore, FilesystemStore, Keys},
+    tls::{self, ReloadableTlsAcceptor},
+    Change, MakeBackupService, ReloadableConfig, ServerConfig, Stats, StorageConfig, Sweeper,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Cli {
     /// Path to the config file
-    #[arg(short, long)]
-    config: PathBuf,
+    ///
+    /// Without a config file, all required settings must be given as
+    /// environment variables or with `--set`.
+    #[arg(short, long, env = "SEKURSRANKO_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Override a setting (e.g. `retention_days=180` or
+    /// `storage.backend=memory`), can be given multiple times
+    ///
+    /// Settings given on the command line take precedence over environment
+    /// variables (e.g. `SEKURSRANKO_RETENTION_DAYS=180`), which take
+    /// precedence over the config file.
+    #[arg(short, long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the server (default)
+    Serve,
+    /// List all backups with their size, last upload and expiry
+    List,
+    /// Show the metadata of a backup
+    Stat { backup_id: String },
+    /// Delete a backup
+    ///
+    /// If `trash_days` is set, the backup is moved to the trash, just like a
+    /// deletion through the API.
+    Delete {
+        backup_id: String,
+        /// Delete the backup permanently, even if `trash_days` is set
+        #[arg(long)]
+        permanent: bool,
+    },
+    /// Delete all backups that were not uploaded within `retention_days`
+    PurgeExpired {
+        /// Only list the expired backups
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show usage statistics of all backups
+    Stats {
+        /// Print the statistics as JSON, like the admin API
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move backups from the flat layout into the sharded layout
+    ///
+    /// Set `sharded = true` in the `[storage]` section of the config file
+    /// first. The migration can be run while the server is running, backups
+    /// uploaded in the meantime are never overwritten. If it is interrupted,
+    /// simply run it again.
+    MigrateSharding,
+    /// Rewrite all stored files with the current encryption key
+    ///
+    /// Add the new key as the first line of the `encryption_key_file` and
+    /// stop the server first. Backups that are not encrypted yet are
+    /// encrypted as well. Once this is done, the old keys can be removed from
+    /// the key file.
+    ReEncrypt,
+    /// Check the config file and list all problems
+    ///
+    /// Exits with a non-zero status if there are any problems.
+    CheckConfig,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
@@ -21,11 +104,47 @@ async fn main() {
     // Parse CLI args
     let cli = Cli::parse();
 
+    // Unknown settings are reported along with all other problems
+    if let Some(Command::CheckConfig) = cli.command {
+        check_config(cli.config.as_deref(), &cli.set);
+        return;
+    }
+
     // Load config
-    let config: ServerConfig = ServerConfig::from_file(&cli.config).unwrap_or_else(|e| {
-        eprintln!("Could not load config file: {}", e);
+    let (config, warnings) = ServerConfig::load(cli.config.as_deref(), env_vars(), &cli.set)
+        .unwrap_or_else(|e| {
+            eprintln!("Could not load config: {}", e);
+            ::std::process::exit(1);
+        });
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    match cli.command {
+        None | Some(Command::Serve) => serve(config, cli.config, cli.set).await,
+        Some(Command::List) => list(config).await,
+        Some(Command::Stat { backup_id }) => stat(config, &backup_id).await,
+        Some(Command::Delete {
+            backup_id,
+            permanent,
+        }) => delete(config, &backup_id, permanent).await,
+        Some(Command::PurgeExpired { dry_run }) => purge_expired(config, dry_run).await,
+        Some(Command::Stats { json }) => stats(config, json).await,
+        Some(Command::MigrateSharding) => migrate_sharding(config).await,
+        Some(Command::ReEncrypt) => reencrypt(config).await,
+        Some(Command::CheckConfig) => unreachable!("checked before loading the config"),
+    }
+}
+
+async fn serve(config: ServerConfig, config_path: Option<PathBuf>, settings: Vec<String>) {
+    let problems = config.validate();
+    if !problems.is_empty() {
+        eprintln!("Invalid configuration:");
+        for problem in &problems {
+            eprintln!("- {}", problem);
+        }
         ::std::process::exit(1);
-    });
+    }
     let addr: ::std::net::SocketAddr = config.listen_on.parse().unwrap_or_else(|e| {
         eprintln!("Invalid listening address: {}", e);
         ::std::process::exit(1);
@@ -36,13 +155,515 @@ async fn main() {
         &config
     );
 
+    // Open storage backend
+    let store = open_store(&config);
+
+    // Start metrics endpoint
+    if let Some(metrics_listen_on) = &config.metrics_listen_on {
+        let listener = tokio::net::TcpListener::bind(metrics_listen_on)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Could not bind metrics to {}: {}", metrics_listen_on, e);
+                ::std::process::exit(1);
+            });
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener).await {
+                error!("Metrics server error: {:#}", e);
+            }
+        });
+    }
+
+    // Load TLS certificate
+    let tls_acceptor = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(
+            ReloadableTlsAcceptor::new(cert, key).unwrap_or_else(|e| {
+                eprintln!("Could not load TLS certificate: {:#}", e);
+                ::std::process::exit(1);
+            }),
+        )),
+        (None, None) => None,
+        _ => {
+            eprintln!("Both tls_cert and tls_key must be set to enable TLS");
+            ::std::process::exit(1);
+        }
+    };
+
+    // Shut down gracefully on SIGINT or SIGTERM
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs.unwrap_or(30));
+    let tls_handshake_timeout =
+        Duration::from_secs(config.tls_handshake_timeout_secs.unwrap_or(10));
+    let shutdown = shutdown_signal();
+    let hangup = hangup_signal();
+
     // Create server
-    let service = MakeBackupService::new(config);
-    let server = Server::bind(&addr).serve(service);
+    let service = MakeBackupService::new(config.clone(), store.clone());
+
+    // Reload the config on SIGHUP
+    tokio::spawn(reload_config_on_hangup(
+        hangup.subscribe(),
+        service.config(),
+        config_path,
+        settings,
+    ));
 
-    // Serve
-    if let Err(e) = server.await {
+    // Start sweeper for expired backups
+    tokio::spawn(
+        Sweeper::new(&config, store.clone())
+            .with_locks(service.locks())
+            .run(),
+    );
+
+    // Run server
+    let connections = ConnectionTasks::default();
+    let server = async {
+        if let Some(tls_acceptor) = tls_acceptor {
+            tokio::spawn(tls_acceptor.clone().watch(hangup.subscribe()));
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Could not bind to {}: {}", addr, e);
+                    ::std::process::exit(1);
+                });
+            Server::builder(tls::incoming(listener, tls_acceptor, tls_handshake_timeout))
+                .executor(connections.clone())
+                .serve(service)
+                .with_graceful_shutdown(wait_for(shutdown.clone()))
+                .await
+        } else {
+            Server::bind(&addr)
+                .executor(connections.clone())
+                .serve(service)
+                .with_graceful_shutdown(wait_for(shutdown.clone()))
+                .await
+        }
+    };
+    let drain_timeout = async {
+        wait_for(shutdown.clone()).await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    let result = tokio::select! {
+        result = server => result,
+        _ = drain_timeout => {
+            warn!(
+                "In-flight requests did not finish within {}s, aborting them",
+                shutdown_timeout.as_secs()
+            );
+            // Make sure that no aborted request touches the store anymore
+            connections.abort_all().await;
+            Ok(())
+        }
+    };
+
+    // Remove the temporary files of aborted uploads
+    match store.cleanup().await {
+        Ok(0) => {}
+        Ok(n) => info!("Removed {} aborted upload(s)", n),
+        Err(e) => error!("Could not remove aborted uploads: {:#}", e),
+    }
+
+    if let Err(e) = result {
         error!("Server error: {}", e);
         std::process::exit(1);
     };
+    info!("Server stopped");
+}
+
+/// Spawns the connection tasks of the server, so that they can be aborted
+/// when they do not finish in time during shutdown.
+#[derive(Clone, Default)]
+struct ConnectionTasks(Arc<Mutex<JoinSet<()>>>);
+
+impl ConnectionTasks {
+    /// Abort all connection tasks and wait until they have stopped.
+    async fn abort_all(&self) {
+        let mut tasks =
+            std::mem::take(&mut *self.0.lock().expect("Connection tasks lock is poisoned"));
+        tasks.shutdown().await;
+    }
+}
+
+impl<F> Executor<F> for ConnectionTasks
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        let mut tasks = self.0.lock().expect("Connection tasks lock is poisoned");
+        // Forget the connections that are already closed
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(fut);
+    }
+}
+
+/// Reload the config whenever `hangup` receives a SIGHUP.
+///
+/// Invalid configs and configs that change settings which require a restart
+/// are rejected, the current config is kept in that case.
+async fn reload_config_on_hangup(
+    mut hangup: broadcast::Receiver<()>,
+    config: Arc<ReloadableConfig>,
+    config_path: Option<PathBuf>,
+    settings: Vec<String>,
+) {
+    loop {
+        // A lagged receiver missed some signals, reloading once covers them all
+        if let Err(RecvError::Closed) = hangup.recv().await {
+            return;
+        }
+        info!("Reloading config after SIGHUP");
+        match reload_config(&config, config_path.as_deref(), &settings) {
+            Ok(changes) if changes.is_empty() => info!("Reloaded config, nothing changed"),
+            Ok(changes) => {
+                for change in changes {
+                    info!("Reloaded config, changed {}", change);
+                }
+            }
+            Err(e) => error!("Could not reload config, keeping the current one: {}", e),
+        }
+    }
+}
+
+fn reload_config(
+    config: &ReloadableConfig,
+    config_path: Option<&Path>,
+    settings: &[String],
+) -> Result<Vec<Change>, String> {
+    let (reloaded, warnings) = ServerConfig::load(config_path, env_vars(), settings)?;
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+    let problems = reloaded.validate();
+    if !problems.is_empty() {
+        return Err(format!("Invalid configuration: {}", problems.join(", ")));
+    }
+    config.reload(reloaded)
+}
+
+/// Return the environment variables that are valid unicode.
+fn env_vars() -> impl Iterator<Item = (String, String)> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+}
+
+/// Install a handler for the given signal, exit if that is not possible.
+fn listen_for(kind: SignalKind, name: &str) -> Signal {
+    signal(kind).unwrap_or_else(|e| {
+        eprintln!("Could not install {} handler: {}", name, e);
+        ::std::process::exit(1);
+    })
+}
+
+/// Return a sender that broadcasts every SIGHUP to its subscribers.
+fn hangup_signal() -> broadcast::Sender<()> {
+    let mut hangup = listen_for(SignalKind::hangup(), "SIGHUP");
+    let (tx, _) = broadcast::channel(1);
+    let sender = tx.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            // Nobody might be subscribed, e.g. without TLS before the reload task started
+            let _ = sender.send(());
+        }
+    });
+    tx
+}
+
+/// Return a receiver that changes once SIGINT or SIGTERM is received.
+fn shutdown_signal() -> watch::Receiver<bool> {
+    let mut interrupt = listen_for(SignalKind::interrupt(), "SIGINT");
+    let mut terminate = listen_for(SignalKind::terminate(), "SIGTERM");
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        let signal = tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        };
+        info!("Received {}, shutting down", signal);
+        let _ = tx.send(true);
+    });
+    rx
+}
+
+/// Wait until the shutdown was requested.
+async fn wait_for(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            // The signal task is gone, so there will be no shutdown
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Open the configured storage backend or exit.
+fn open_store(config: &ServerConfig) -> Arc<dyn BackupStore> {
+    storage::from_config(config).unwrap_or_else(|e| {
+        eprintln!("Could not open storage backend: {:#}", e);
+        ::std::process::exit(1);
+    })
+}
+
+/// Exit unless the backup id is valid.
+fn check_backup_id(backup_id: &str) {
+    if !backup_id_valid(backup_id) {
+        eprintln!("Invalid backup id: {}", backup_id);
+        ::std::process::exit(1);
+    }
+}
+
+/// Return the age of a backup in days, and the number of days until it
+/// expires or `None` if it has expired already.
+fn age_days(config: &ServerConfig, modified: SystemTime) -> (u64, Option<u64>) {
+    const DAY: u64 = 24 * 3600;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default()
+        .as_secs();
+    let retention = u64::from(config.retention_days) * DAY;
+    // Backups expire just like in the sweeper
+    let expires_in = if age > retention {
+        None
+    } else {
+        Some((retention - age) / DAY)
+    };
+    (age / DAY, expires_in)
+}
+
+fn format_expiry(expires_in: Option<u64>) -> String {
+    match expires_in {
+        Some(days) => format!("{}d", days),
+        None => "expired".to_string(),
+    }
+}
+
+async fn list(config: ServerConfig) {
+    let store = open_store(&config);
+    let mut backups = store.list().await.unwrap_or_else(|e| {
+        eprintln!("Could not list backups: {:#}", e);
+        ::std::process::exit(1);
+    });
+    backups.sort_by_key(|(_, metadata)| metadata.modified);
+    println!(
+        "{:<64}  {:>10}  {:<29}  {:>6}  {:>10}",
+        "BACKUP ID", "SIZE", "LAST UPLOAD", "AGE", "EXPIRES IN"
+    );
+    let mut expired = 0;
+    for (backup_id, metadata) in &backups {
+        let (age, expires_in) = age_days(&config, metadata.modified);
+        if expires_in.is_none() {
+            expired += 1;
+        }
+        println!(
+            "{:<64}  {:>10}  {:<29}  {:>6}  {:>10}",
+            backup_id,
+            metadata.size,
+            httpdate::fmt_http_date(metadata.modified),
+            format!("{}d", age),
+            format_expiry(expires_in)
+        );
+    }
+    println!("\n{} backup(s), {} expired", backups.len(), expired);
+}
+
+async fn stat(config: ServerConfig, backup_id: &str) {
+    check_backup_id(backup_id);
+    let store = open_store(&config);
+    let metadata = match store.stat(backup_id).await {
+        Ok(Some(metadata)) => metadata,
+        Ok(None) => {
+            eprintln!("Backup {} not found", backup_id);
+            ::std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Could not look up backup: {:#}", e);
+            ::std::process::exit(1);
+        }
+    };
+    let versions = store.versions(backup_id).await.unwrap_or_else(|e| {
+        eprintln!("Could not list versions of backup: {:#}", e);
+        ::std::process::exit(1);
+    });
+    let (age, expires_in) = age_days(&config, metadata.modified);
+    println!("Backup ID:   {}", backup_id);
+    println!("Size:        {} bytes", metadata.size);
+    println!(
+        "Last upload: {} ({} days ago)",
+        httpdate::fmt_http_date(metadata.modified),
+        age
+    );
+    println!("Expires in:  {}", format_expiry(expires_in));
+    if let Some(etag) = &metadata.etag {
+        println!("ETag:        {}", etag);
+    }
+    println!("Versions:    {}", versions.len());
+}
+
+async fn delete(config: ServerConfig, backup_id: &str, permanent: bool) {
+    check_backup_id(backup_id);
+    let store = open_store(&config);
+    let trash = !permanent && config.trash_days.unwrap_or(0) > 0;
+    let deleted = if trash {
+        store.trash(backup_id).await
+    } else {
+        store.delete(backup_id).await
+    };
+    match deleted {
+        Ok(true) if trash => println!("Moved backup {} to the trash", backup_id),
+        Ok(true) => println!("Deleted backup {}", backup_id),
+        Ok(false) => {
+            eprintln!("Backup {} not found", backup_id);
+            ::std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Could not delete backup: {:#}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+async fn purge_expired(config: ServerConfig, dry_run: bool) {
+    let sweeper = Sweeper::new(&config, open_store(&config));
+    if !dry_run {
+        match sweeper.sweep().await {
+            Ok(deleted) => println!("Deleted {} expired backup(s)", deleted),
+            Err(e) => {
+                eprintln!("Could not delete expired backups: {:#}", e);
+                ::std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let expired = sweeper.expired_backups().await.unwrap_or_else(|e| {
+        eprintln!("Could not list expired backups: {:#}", e);
+        ::std::process::exit(1);
+    });
+    for (backup_id, age) in &expired {
+        println!(
+            "{} (last upload {} days ago)",
+            backup_id,
+            age.as_secs() / (24 * 3600)
+        );
+    }
+    println!("{} expired backup(s) would be deleted", expired.len());
+}
+
+async fn stats(config: ServerConfig, json: bool) {
+    let store = open_store(&config);
+    let stats = Stats::collect(&*store, config.retention_days, SystemTime::now())
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Could not collect backup statistics: {:#}", e);
+            ::std::process::exit(1);
+        });
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&stats).expect("Could not serialize statistics")
+        );
+        return;
+    }
+
+    let percentiles = &stats.size_percentiles;
+    println!("Backups:          {}", stats.backups);
+    println!("Total size:       {} bytes", stats.total_bytes);
+    println!("Average size:     {} bytes", stats.average_bytes);
+    println!(
+        "Size percentiles: p50 {}, p90 {}, p99 {}, max {} bytes",
+        percentiles.p50, percentiles.p90, percentiles.p99, percentiles.max
+    );
+    println!("\nAge (days)       Backups");
+    for bucket in &stats.age_histogram {
+        let range = match bucket.max_days {
+            Some(max_days) => format!("{}-{}", bucket.min_days, max_days),
+            None => format!("{}+ (expired)", bucket.min_days),
+        };
+        println!("{:<16} {:>7}", range, bucket.backups);
+    }
+    println!(
+        "\nExpiring within {} days: {}",
+        stats.near_expiry.within_days, stats.near_expiry.backups
+    );
+    for backup in &stats.near_expiry.soonest {
+        println!(
+            "  {}  {:>10} bytes  expires in {}d",
+            backup.backup_id, backup.size, backup.expires_in_days
+        );
+    }
+    println!("\nLargest backups:");
+    for backup in &stats.largest {
+        println!("  {}  {:>10} bytes", backup.backup_id, backup.size);
+    }
+}
+
+async fn migrate_sharding(config: ServerConfig) {
+    let store = match config.storage.clone().unwrap_or_default() {
+        StorageConfig::Filesystem(fs_config) => {
+            FilesystemStore::new(&config.backup_dir, &fs_config)
+        }
+        other => {
+            eprintln!(
+                "Migration is not supported for the {} storage backend",
+                other
+            );
+            ::std::process::exit(1);
+        }
+    };
+    match store.migrate_to_sharded().await {
+        Ok(migrated) => println!("Migrated {} backup(s) to the sharded layout", migrated),
+        Err(e) => {
+            eprintln!("Migration failed: {:#}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+async fn reencrypt(config: ServerConfig) {
+    let store = match config.storage.clone().unwrap_or_default() {
+        StorageConfig::Filesystem(fs_config) => {
+            FilesystemStore::new(&config.backup_dir, &fs_config)
+        }
+        other => {
+            eprintln!(
+                "Encryption is not supported for the {} storage backend",
+                other
+            );
+            ::std::process::exit(1);
+        }
+    };
+    let keys = match &config.encryption_key_file {
+        Some(path) => Keys::from_file(path).unwrap_or_else(|e| {
+            eprintln!("Could not load encryption keys: {:#}", e);
+            ::std::process::exit(1);
+        }),
+        None => {
+            eprintln!("An encryption_key_file must be configured before re-encrypting");
+            ::std::process::exit(1);
+        }
+    };
+    match store.with_encryption(Arc::new(keys)).reencrypt().await {
+        Ok(rewritten) => println!("Re-encrypted {} file(s)", rewritten),
+        Err(e) => {
+            eprintln!("Re-encryption failed: {:#}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn check_config(config_path: Option<&Path>, settings: &[String]) {
+    let (config, warnings, unknown) = ServerConfig::load_lenient(config_path, env_vars(), settings)
+        .unwrap_or_else(|e| {
+            eprintln!("Could not load config: {}", e);
+            ::std::process::exit(1);
+        });
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    let mut problems = unknown;
+    problems.extend(config.validate());
+    if problems.is_empty() {
+        println!("The configuration is valid:\n\n{}", config);
+        return;
+    }
+    for problem in &problems {
+        eprintln!("- {}", problem);
+    }
+    eprintln!("Found {} problem(s) in the configuration", problems.len());
+    ::std::process::exit(1);
 }
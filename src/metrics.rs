@@ -0,0 +1,349 @@
+//! Prometheus metrics.
+//!
+//! The metrics are collected in a global registry and exported in the
+//! Prometheus text format on a separate listening address.
+
+use std::{
+    collections::BTreeMap,
+    convert::{Infallible, TryFrom},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use hyper::{
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::net::TcpListener;
+
+use crate::routing::Route;
+
+/// The upper bounds of the request latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A monotonically increasing counter.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A histogram of durations.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// The number of observations per bucket, not cumulative
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// All metrics of the server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Requests by route, method and status
+    requests: Mutex<BTreeMap<(&'static str, String, u16), u64>>,
+    /// Request latency by route
+    latency: Mutex<BTreeMap<&'static str, Arc<Histogram>>>,
+    /// Bytes received in successful uploads
+    pub upload_bytes: Counter,
+    /// Bytes sent in successful downloads
+    pub download_bytes: Counter,
+    /// Backups deleted by the sweeper
+    pub sweeper_deletions: Counter,
+    /// Temporary files of interrupted uploads removed by the sweeper
+    pub orphans_removed: Counter,
+    /// Backups permanently deleted from the trash by the sweeper
+    pub trash_purged: Counter,
+    /// Stored backups, as of the last sweep
+    pub backups: Gauge,
+    /// Total size of all stored backups, as of the last sweep
+    pub stored_bytes: Gauge,
+    /// Latency of flushing backup files to disk
+    pub fsync_file: Histogram,
+    /// Latency of flushing the backup directory to disk after a rename
+    pub fsync_dir: Histogram,
+}
+
+/// Return the global metrics.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+fn route_label(route: Option<Route>) -> &'static str {
+    match route {
+        Some(Route::Index) => "index",
+        Some(Route::Config) => "config",
+        Some(Route::Backup) => "backup",
+        Some(Route::Healthz) => "healthz",
+        Some(Route::Readyz) => "readyz",
+        Some(
+            Route::AdminVersions
+            | Route::AdminRestore
+            | Route::AdminTrash
+            | Route::AdminUntrash
+            | Route::AdminStats
+            | Route::AdminReadiness,
+        ) => "admin",
+        None => "unknown",
+    }
+}
+
+impl Metrics {
+    /// Record a finished request.
+    pub fn observe_request(
+        &self,
+        route: Option<Route>,
+        method: &Method,
+        status: StatusCode,
+        duration: Duration,
+    ) {
+        let route = route_label(route);
+        // Don't let clients create arbitrary label values
+        let method = match *method {
+            Method::GET
+            | Method::HEAD
+            | Method::PUT
+            | Method::POST
+            | Method::DELETE
+            | Method::OPTIONS => method.as_str().to_string(),
+            _ => "other".to_string(),
+        };
+        *self
+            .requests
+            .lock()
+            .expect("Metrics lock is poisoned")
+            .entry((route, method, status.as_u16()))
+            .or_insert(0) += 1;
+        let histogram = self
+            .latency
+            .lock()
+            .expect("Metrics lock is poisoned")
+            .entry(route)
+            .or_default()
+            .clone();
+        histogram.observe(duration);
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP sekursranko_requests_total Number of HTTP requests.\n");
+        out.push_str("# TYPE sekursranko_requests_total counter\n");
+        for ((route, method, status), count) in self
+            .requests
+            .lock()
+            .expect("Metrics lock is poisoned")
+            .iter()
+        {
+            let _ = writeln!(
+                out,
+                "sekursranko_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                route, method, status, count
+            );
+        }
+
+        out.push_str(
+            "# HELP sekursranko_request_duration_seconds Time until the response headers were sent.\n",
+        );
+        out.push_str("# TYPE sekursranko_request_duration_seconds histogram\n");
+        for (route, histogram) in self
+            .latency
+            .lock()
+            .expect("Metrics lock is poisoned")
+            .iter()
+        {
+            histogram.render(
+                &mut out,
+                "sekursranko_request_duration_seconds",
+                &format!("route=\"{}\"", route),
+            );
+        }
+
+        out.push_str("# HELP sekursranko_fsync_duration_seconds Time to flush data to disk.\n");
+        out.push_str("# TYPE sekursranko_fsync_duration_seconds histogram\n");
+        self.fsync_file.render(
+            &mut out,
+            "sekursranko_fsync_duration_seconds",
+            "target=\"file\"",
+        );
+        self.fsync_dir.render(
+            &mut out,
+            "sekursranko_fsync_duration_seconds",
+            "target=\"dir\"",
+        );
+
+        render_counter(
+            &mut out,
+            "sekursranko_upload_bytes_total",
+            "Bytes received in successful uploads.",
+            &self.upload_bytes,
+        );
+        render_counter(
+            &mut out,
+            "sekursranko_download_bytes_total",
+            "Bytes sent in successful downloads.",
+            &self.download_bytes,
+        );
+        render_counter(
+            &mut out,
+            "sekursranko_sweeper_deletions_total",
+            "Expired backups deleted by the sweeper.",
+            &self.sweeper_deletions,
+        );
+        render_counter(
+            &mut out,
+            "sekursranko_orphans_removed_total",
+            "Temporary files of interrupted uploads removed by the sweeper.",
+            &self.orphans_removed,
+        );
+        render_counter(
+            &mut out,
+            "sekursranko_trash_purged_total",
+            "Deleted backups purged from the trash by the sweeper.",
+            &self.trash_purged,
+        );
+
+        // Listing all backups can be expensive, so the sweeper counts them
+        render_gauge(
+            &mut out,
+            "sekursranko_backups",
+            "Number of stored backups as of the last sweep.",
+            &self.backups,
+        );
+        render_gauge(
+            &mut out,
+            "sekursranko_stored_bytes",
+            "Total size of all stored backups as of the last sweep.",
+            &self.stored_bytes,
+        );
+
+        out
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, counter.get());
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, gauge.get());
+}
+
+async fn handle_metrics(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if req.uri().path() != "/metrics" {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    } else if req.method() != Method::GET {
+        Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+    } else {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics().render()))
+    };
+    Ok(response.expect("Could not create response"))
+}
+
+/// Serve the metrics at `/metrics` on the listener.
+pub async fn serve(listener: TcpListener) -> anyhow::Result<()> {
+    let incoming = AddrIncoming::from_listener(listener)?;
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle_metrics)) });
+    Server::builder(incoming).serve(make_service).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(1));
+        histogram.observe(Duration::from_millis(200));
+        histogram.observe(Duration::from_secs(60));
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "route=\"backup\"");
+        assert!(out.contains("latency_bucket{route=\"backup\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("latency_bucket{route=\"backup\",le=\"0.25\"} 2\n"));
+        assert!(out.contains("latency_bucket{route=\"backup\",le=\"10\"} 2\n"));
+        assert!(out.contains("latency_bucket{route=\"backup\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_sum{route=\"backup\"} 60.201\n"));
+        assert!(out.contains("latency_count{route=\"backup\"} 3\n"));
+    }
+}
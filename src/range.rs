@@ -0,0 +1,91 @@
+//! Parsing of `Range` request headers (RFC 7233).
+
+use std::ops::Range;
+
+/// The result of evaluating a `Range` header against the size of a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+    /// The bytes to send
+    Satisfiable(Range<u64>),
+    /// The range lies completely outside of the backup
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header value for a backup of `size` bytes.
+///
+/// Only a single range in `bytes` is supported. Return `None` if the header
+/// should be ignored and the whole backup sent instead, which is the case for
+/// invalid headers, other units and multiple ranges.
+pub fn parse(value: &str, size: u64) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let parse_pos = |pos: &str| {
+        if pos.bytes().all(|b| b.is_ascii_digit()) {
+            pos.parse::<u64>().ok()
+        } else {
+            None
+        }
+    };
+    if first.is_empty() {
+        // Suffix range: The last `len` bytes
+        let len = parse_pos(last)?;
+        if len == 0 || size == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable(size.saturating_sub(len)..size));
+    }
+    let first = parse_pos(first)?;
+    let end = if last.is_empty() {
+        size
+    } else {
+        let last = parse_pos(last)?;
+        if last < first {
+            return None;
+        }
+        last.saturating_add(1).min(size)
+    };
+    if first >= size {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable(first..end))
+}
+
+/// Return the `Content-Range` header value for a range of a backup.
+pub fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        use ByteRange::*;
+        assert_eq!(parse("bytes=0-99", 1000), Some(Satisfiable(0..100)));
+        assert_eq!(parse("bytes=900-", 1000), Some(Satisfiable(900..1000)));
+        assert_eq!(parse("bytes=900-2000", 1000), Some(Satisfiable(900..1000)));
+        assert_eq!(parse("bytes=-100", 1000), Some(Satisfiable(900..1000)));
+        assert_eq!(parse("bytes=-2000", 1000), Some(Satisfiable(0..1000)));
+        assert_eq!(parse("bytes=999-999", 1000), Some(Satisfiable(999..1000)));
+        assert_eq!(parse("bytes=1000-", 1000), Some(Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 1000), Some(Unsatisfiable));
+        assert_eq!(parse("bytes=0-", 0), Some(Unsatisfiable));
+        assert_eq!(parse("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse("bytes=5-1", 1000), None);
+        assert_eq!(parse("bytes=+5-6", 1000), None);
+        assert_eq!(parse("bytes=-", 1000), None);
+        assert_eq!(parse("items=0-1", 1000), None);
+        assert_eq!(parse("garbage", 1000), None);
+    }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(content_range(&(0..100), 1000), "bytes 0-99/1000");
+        assert_eq!(content_range(&(999..1000), 1000), "bytes 999-999/1000");
+    }
+}
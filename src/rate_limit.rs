@@ -0,0 +1,326 @@
+//! Token bucket rate limiting.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::Method;
+
+use crate::config::RateLimitConfig;
+
+/// Remove idle buckets once the number of buckets exceeds this value.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The minimum time between two prunes, so that a flood of clients does not
+/// cause a full scan of the buckets on every request.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The request classes that have separate budgets.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    /// GET and HEAD requests
+    Download,
+    /// PUT requests
+    Upload,
+    /// DELETE requests
+    Delete,
+}
+
+impl RequestClass {
+    pub fn from_method(method: &Method) -> Option<Self> {
+        match *method {
+            Method::GET | Method::HEAD => Some(RequestClass::Download),
+            Method::PUT => Some(RequestClass::Upload),
+            Method::DELETE => Some(RequestClass::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Ip(RequestClass, IpAddr),
+    BackupId(RequestClass, String),
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    /// The time of the last prune
+    pruned: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The budget for a request class.
+#[derive(Debug, Copy, Clone)]
+struct Budget {
+    /// The maximum number of tokens
+    capacity: f64,
+    /// The number of tokens added per second
+    rate: f64,
+}
+
+impl Budget {
+    fn new(per_minute: Option<u32>, burst: Option<u32>) -> Option<Self> {
+        match per_minute {
+            Some(per_minute) if per_minute > 0 => Some(Self {
+                capacity: f64::from(burst.unwrap_or(per_minute).max(1)),
+                rate: f64::from(per_minute) / 60.0,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A rate limiter with a token bucket per client IP and request class, and
+/// optionally per backup id and request class.
+#[derive(Debug)]
+pub struct RateLimiter {
+    download: Option<Budget>,
+    upload: Option<Budget>,
+    delete: Option<Budget>,
+    per_backup_id: bool,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            download: Budget::new(config.download_per_minute, config.burst),
+            upload: Budget::new(config.upload_per_minute, config.burst),
+            delete: Budget::new(config.delete_per_minute, config.burst),
+            per_backup_id: config.per_backup_id.unwrap_or(false),
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned: None,
+            }),
+        }
+    }
+
+    fn budget(&self, class: RequestClass) -> Option<Budget> {
+        match class {
+            RequestClass::Download => self.download,
+            RequestClass::Upload => self.upload,
+            RequestClass::Delete => self.delete,
+        }
+    }
+
+    /// Take a token for a request.
+    ///
+    /// Return `Err` with the time until the request would be allowed if the
+    /// budget is exhausted.
+    pub fn check(
+        &self,
+        class: RequestClass,
+        ip: Option<IpAddr>,
+        backup_id: &str,
+    ) -> Result<(), Duration> {
+        self.check_at(class, ip, backup_id, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        class: RequestClass,
+        ip: Option<IpAddr>,
+        backup_id: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let budget = match self.budget(class) {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+
+        let mut keys = Vec::with_capacity(2);
+        if let Some(ip) = ip {
+            keys.push(BucketKey::Ip(class, normalize_ip(ip)));
+        }
+        if self.per_backup_id {
+            keys.push(BucketKey::BackupId(class, backup_id.to_string()));
+        }
+
+        let mut guard = self.buckets.lock().expect("Rate limiter lock is poisoned");
+        let Buckets { buckets, pruned } = &mut *guard;
+        let prune_due = !pruned.is_some_and(|pruned| now < pruned + PRUNE_INTERVAL);
+        if buckets.len() > PRUNE_THRESHOLD && prune_due {
+            // Buckets that would be full again carry no information
+            buckets.retain(|key, bucket| {
+                let class = match key {
+                    BucketKey::Ip(class, _) | BucketKey::BackupId(class, _) => *class,
+                };
+                self.budget(class)
+                    .is_some_and(|budget| refill(bucket, budget, now) < budget.capacity)
+            });
+            *pruned = Some(now);
+        }
+
+        // Only take tokens if all buckets allow the request
+        let mut wait = Duration::from_secs(0);
+        for key in &keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: budget.capacity,
+                updated: now,
+            });
+            let tokens = refill(bucket, budget, now);
+            if tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - tokens) / budget.rate));
+            }
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+        for key in &keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Add the tokens accumulated since the last update and return the new number of tokens.
+fn refill(bucket: &mut Bucket, budget: Budget, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * budget.rate).min(budget.capacity);
+    bucket.updated = now;
+    bucket.tokens
+}
+
+/// Group IPv6 addresses by their /64 prefix, since a single client usually
+/// controls a whole /64 network.
+fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ipv4) => IpAddr::V4(ipv4),
+            None => {
+                let mut segments = ip.segments();
+                segments[4..].iter_mut().for_each(|s| *s = 0);
+                IpAddr::V6(segments.into())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn limiter(per_backup_id: bool) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            download_per_minute: Some(60),
+            upload_per_minute: Some(6),
+            delete_per_minute: None,
+            burst: Some(2),
+            per_backup_id: Some(per_backup_id),
+        })
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = limiter(false);
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let now = Instant::now();
+        let check = |class, after| limiter.check_at(class, ip, BACKUP_ID, now + after);
+        let wait_secs = |result: Result<(), Duration>| result.unwrap_err().as_secs_f64().round();
+
+        // Burst of two requests, then one token every 10 seconds
+        assert_eq!(check(RequestClass::Upload, Duration::from_secs(0)), Ok(()));
+        assert_eq!(check(RequestClass::Upload, Duration::from_secs(0)), Ok(()));
+        assert_eq!(
+            wait_secs(check(RequestClass::Upload, Duration::from_secs(0))),
+            10.0
+        );
+        assert_eq!(
+            wait_secs(check(RequestClass::Upload, Duration::from_secs(4))),
+            6.0
+        );
+        assert_eq!(check(RequestClass::Upload, Duration::from_secs(10)), Ok(()));
+
+        // Other classes have separate budgets
+        assert_eq!(
+            check(RequestClass::Download, Duration::from_secs(10)),
+            Ok(())
+        );
+        for _ in 0..100 {
+            assert_eq!(check(RequestClass::Delete, Duration::from_secs(10)), Ok(()));
+        }
+
+        // Other clients have separate budgets
+        let other_ip = Some("192.0.2.2".parse().unwrap());
+        assert_eq!(
+            limiter.check_at(RequestClass::Upload, other_ip, BACKUP_ID, now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_per_backup_id() {
+        let limiter = limiter(true);
+        let now = Instant::now();
+        for ip in &["192.0.2.1", "192.0.2.2"] {
+            let ip = Some(ip.parse().unwrap());
+            assert_eq!(
+                limiter.check_at(RequestClass::Upload, ip, BACKUP_ID, now),
+                Ok(())
+            );
+        }
+        let ip = Some("192.0.2.3".parse().unwrap());
+        assert!(limiter
+            .check_at(RequestClass::Upload, ip, BACKUP_ID, now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let limiter = limiter(false);
+        let now = Instant::now();
+        let fill = |offset: u32, at: Duration| {
+            for i in 0..=PRUNE_THRESHOLD as u32 {
+                let ip = Some(IpAddr::from((offset + i).to_be_bytes()));
+                assert_eq!(
+                    limiter.check_at(RequestClass::Download, ip, BACKUP_ID, now + at),
+                    Ok(())
+                );
+            }
+        };
+        let check_len = |at: Duration| {
+            let ip = Some("192.0.2.1".parse().unwrap());
+            assert_eq!(
+                limiter.check_at(RequestClass::Download, ip, BACKUP_ID, now + at),
+                Ok(())
+            );
+            limiter.buckets.lock().unwrap().buckets.len()
+        };
+
+        // Idle buckets are full again after a second and removed
+        fill(0, Duration::from_secs(0));
+        assert_eq!(check_len(Duration::from_secs(5)), 1);
+
+        // No further prune until the interval has passed
+        fill(1 << 24, Duration::from_secs(6));
+        assert!(check_len(Duration::from_secs(10)) > PRUNE_THRESHOLD);
+        assert_eq!(check_len(Duration::from_secs(15)), 1);
+    }
+
+    #[test]
+    fn test_normalize_ip() {
+        assert_eq!(
+            normalize_ip("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            normalize_ip("::ffff:192.0.2.1".parse().unwrap()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -6,6 +6,29 @@ pub enum Route {
     Index,
     Config,
     Backup,
+    Healthz,
+    Readyz,
+    AdminVersions,
+    AdminRestore,
+    AdminTrash,
+    AdminUntrash,
+    AdminStats,
+    AdminReadiness,
+}
+
+impl Route {
+    /// Return whether this is a route of the admin API.
+    pub fn is_admin(self) -> bool {
+        matches!(
+            self,
+            Route::AdminVersions
+                | Route::AdminRestore
+                | Route::AdminTrash
+                | Route::AdminUntrash
+                | Route::AdminStats
+                | Route::AdminReadiness
+        )
+    }
 }
 
 /// Create a new router instance.
@@ -14,5 +37,16 @@ pub fn make_router() -> Router {
     router.add("/", Route::Index);
     router.add("/config", Route::Config);
     router.add("/backups/:backupId", Route::Backup);
+    router.add("/healthz", Route::Healthz);
+    router.add("/readyz", Route::Readyz);
+    router.add("/admin/backups/:backupId/versions", Route::AdminVersions);
+    router.add(
+        "/admin/backups/:backupId/versions/:version/restore",
+        Route::AdminRestore,
+    );
+    router.add("/admin/trash", Route::AdminTrash);
+    router.add("/admin/trash/:backupId/restore", Route::AdminUntrash);
+    router.add("/admin/stats", Route::AdminStats);
+    router.add("/admin/readiness", Route::AdminReadiness);
     router
 }
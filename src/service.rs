@@ -1,26 +1,43 @@
 use std::{
     future::Future,
+    net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Instant,
 };
 
-use hyper::{service::Service, Body, Request, Response};
+use hyper::{server::conn::AddrStream, service::Service, Body, Request, Response};
 use log::trace;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
 
 use crate::{
-    config::ServerConfig,
+    config::{ReloadableConfig, ServerConfig},
     handlers::handler,
+    locks::BackupLocks,
+    metrics::metrics,
+    rate_limit::RateLimiter,
     routing::{make_router, Router},
+    storage::BackupStore,
 };
 
 // Note: Implementation based on `service_struct_impl.rs` example in the hyper repo.
 
-/// A `BackupService` wraps a configuration and a reference counted file system pool.
-#[derive(Debug, Clone)]
+/// The state shared by all connections.
+pub(crate) struct State {
+    pub config: Arc<ReloadableConfig>,
+    pub router: Router,
+    pub store: Arc<dyn BackupStore>,
+    pub rate_limiter: RateLimiter,
+    pub locks: Arc<BackupLocks>,
+}
+
+/// A `BackupService` wraps the shared state and the address of the client.
+#[derive(Clone)]
 pub struct BackupService {
-    config: Arc<ServerConfig>,
-    router: Arc<Router>,
+    state: Arc<State>,
+    remote_addr: Option<SocketAddr>,
 }
 
 type PinBox<T> = Pin<Box<T>>;
@@ -38,29 +55,76 @@ impl Service<Request<Body>> for BackupService {
         trace!("BackupService::call");
 
         // Copy Arc references that will be moved into the future
-        let config = self.config.clone();
-        let router = self.router.clone();
+        let state = self.state.clone();
+        let remote_addr = self.remote_addr;
 
         // Call handler
-        Box::pin(async move { handler(req, &router, &config).await })
+        Box::pin(async move {
+            let started = Instant::now();
+            let method = req.method().clone();
+            let route = state
+                .router
+                .recognize(req.uri().path())
+                .ok()
+                .map(|route_match| **route_match.handler());
+            let response = handler(req, &state, remote_addr).await?;
+            metrics().observe_request(route, &method, response.status(), started.elapsed());
+            Ok(response)
+        })
+    }
+}
+
+/// A connection with a known remote address.
+pub trait RemoteAddr {
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+impl RemoteAddr for AddrStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(AddrStream::remote_addr(self))
+    }
+}
+
+impl RemoteAddr for TlsStream<TcpStream> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
     }
 }
 
 pub struct MakeBackupService {
-    config: Arc<ServerConfig>,
-    router: Arc<Router>,
+    state: Arc<State>,
 }
 
 impl MakeBackupService {
-    pub fn new(config: ServerConfig) -> Self {
+    pub fn new(config: ServerConfig, store: Arc<dyn BackupStore>) -> Self {
+        let rate_limiter = RateLimiter::new(&config.rate_limit.clone().unwrap_or_default());
         Self {
-            config: Arc::new(config),
-            router: Arc::new(make_router()),
+            state: Arc::new(State {
+                config: Arc::new(ReloadableConfig::new(config)),
+                router: make_router(),
+                store,
+                rate_limiter,
+                locks: Arc::new(BackupLocks::new()),
+            }),
         }
     }
+
+    /// Return the locks that serialize modifications of a backup.
+    ///
+    /// Background tasks that modify backups should use these as well.
+    pub fn locks(&self) -> Arc<BackupLocks> {
+        self.state.locks.clone()
+    }
+
+    /// Return the config used by the service.
+    ///
+    /// Reloading it changes the config for all following requests.
+    pub fn config(&self) -> Arc<ReloadableConfig> {
+        self.state.config.clone()
+    }
 }
 
-impl<T> Service<T> for MakeBackupService {
+impl<'a, T: RemoteAddr> Service<&'a T> for MakeBackupService {
     type Response = BackupService;
     type Error = hyper::Error;
     type Future = PinBox<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>;
@@ -69,10 +133,10 @@ impl<T> Service<T> for MakeBackupService {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
-        let config = self.config.clone();
-        let router = self.router.clone();
-        let fut = async move { Ok(BackupService { config, router }) };
+    fn call(&mut self, conn: &'a T) -> Self::Future {
+        let state = self.state.clone();
+        let remote_addr = conn.remote_addr();
+        let fut = async move { Ok(BackupService { state, remote_addr }) };
         Box::pin(fut)
     }
 }
@@ -0,0 +1,245 @@
+//! Usage statistics of the backup store.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::Serialize;
+
+use crate::storage::{BackupMetadata, BackupStore};
+
+/// The number of days before their expiry in which backups are considered
+/// near expiry.
+const NEAR_EXPIRY_DAYS: u64 = 30;
+
+/// The number of backups listed as largest and soonest to expire.
+const TOP_BACKUPS: usize = 10;
+
+const DAY: u64 = 24 * 3600;
+
+/// Statistics about all stored backups.
+///
+/// Sizes are the sizes in the storage backend, so that they can be used to
+/// plan capacity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub backups: usize,
+    pub total_bytes: u64,
+    pub average_bytes: u64,
+    pub size_percentiles: SizePercentiles,
+    /// The number of backups by age, in quarters of the retention period
+    /// followed by the expired backups
+    pub age_histogram: Vec<AgeBucket>,
+    pub near_expiry: NearExpiry,
+    /// The largest backups, largest first
+    pub largest: Vec<BackupInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SizePercentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// The number of backups that were last uploaded between `min_days` and
+/// `max_days` ago.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgeBucket {
+    pub min_days: u64,
+    /// The upper bound, or `None` for the bucket of expired backups
+    pub max_days: Option<u64>,
+    pub backups: usize,
+}
+
+/// The backups that will expire within `within_days`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NearExpiry {
+    pub within_days: u64,
+    pub backups: usize,
+    /// The backups that expire first, soonest first
+    pub soonest: Vec<BackupInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupInfo {
+    pub backup_id: String,
+    pub size: u64,
+    /// The time of the last upload in seconds since the Unix epoch
+    pub modified: u64,
+    /// The number of whole days until the backup expires
+    pub expires_in_days: u64,
+}
+
+impl Stats {
+    /// Compute the statistics of all backups in a store.
+    pub async fn collect(
+        store: &dyn BackupStore,
+        retention_days: u32,
+        now: SystemTime,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::compute(&store.list().await?, retention_days, now))
+    }
+
+    /// Compute the statistics of a list of backups.
+    ///
+    /// Backups expire once they are older than the retention period, just
+    /// like in the sweeper.
+    pub fn compute(
+        backups: &[(String, BackupMetadata)],
+        retention_days: u32,
+        now: SystemTime,
+    ) -> Self {
+        let retention = u64::from(retention_days) * DAY;
+        let age = |metadata: &BackupMetadata| {
+            now.duration_since(metadata.modified)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let info = |(backup_id, metadata): &(String, BackupMetadata)| BackupInfo {
+            backup_id: backup_id.clone(),
+            size: metadata.size,
+            modified: metadata
+                .modified
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            expires_in_days: retention.saturating_sub(age(metadata)) / DAY,
+        };
+
+        let mut sizes: Vec<u64> = backups.iter().map(|(_, metadata)| metadata.size).collect();
+        sizes.sort_unstable();
+        let total_bytes = sizes.iter().sum();
+        let percentile = |p: u64| {
+            // Nearest rank
+            let rank = (p * sizes.len() as u64).div_ceil(100);
+            sizes
+                .get((rank as usize).saturating_sub(1))
+                .copied()
+                .unwrap_or(0)
+        };
+
+        let mut age_histogram: Vec<AgeBucket> = (0..4)
+            .map(|quarter| AgeBucket {
+                min_days: u64::from(retention_days) * quarter / 4,
+                max_days: Some(u64::from(retention_days) * (quarter + 1) / 4),
+                backups: 0,
+            })
+            .collect();
+        age_histogram.push(AgeBucket {
+            min_days: u64::from(retention_days),
+            max_days: None,
+            backups: 0,
+        });
+        for (_, metadata) in backups {
+            let age = age(metadata);
+            let bucket = if age > retention {
+                4
+            } else {
+                // The last quarter includes the end of the retention period
+                ((age * 4) / retention.max(1)).min(3) as usize
+            };
+            age_histogram[bucket].backups += 1;
+        }
+
+        let near_expiry = NEAR_EXPIRY_DAYS * DAY;
+        let mut expiring: Vec<_> = backups
+            .iter()
+            .filter(|(_, metadata)| {
+                let age = age(metadata);
+                age <= retention && retention - age <= near_expiry
+            })
+            .collect();
+        expiring.sort_by_key(|(_, metadata)| metadata.modified);
+        let mut largest: Vec<_> = backups.iter().collect();
+        largest.sort_by(|(a_id, a), (b_id, b)| b.size.cmp(&a.size).then(a_id.cmp(b_id)));
+
+        Self {
+            backups: backups.len(),
+            total_bytes,
+            average_bytes: total_bytes.checked_div(sizes.len() as u64).unwrap_or(0),
+            size_percentiles: SizePercentiles {
+                p50: percentile(50),
+                p90: percentile(90),
+                p99: percentile(99),
+                max: sizes.last().copied().unwrap_or(0),
+            },
+            age_histogram,
+            near_expiry: NearExpiry {
+                within_days: NEAR_EXPIRY_DAYS,
+                backups: expiring.len(),
+                soonest: expiring.into_iter().take(TOP_BACKUPS).map(info).collect(),
+            },
+            largest: largest.into_iter().take(TOP_BACKUPS).map(info).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_compute_empty() {
+        let stats = Stats::compute(&[], 180, SystemTime::now());
+        assert_eq!(stats.backups, 0);
+        assert_eq!(stats.average_bytes, 0);
+        assert_eq!(stats.size_percentiles.p99, 0);
+        assert_eq!(stats.age_histogram.len(), 5);
+        assert!(stats.largest.is_empty());
+    }
+
+    #[test]
+    fn test_compute() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        let backup = |n: usize, size: u64, age_days: u64| {
+            (
+                format!("{:064x}", n),
+                BackupMetadata {
+                    size,
+                    modified: now - Duration::from_secs(age_days * DAY),
+                    etag: None,
+                },
+            )
+        };
+        let mut backups: Vec<_> = (1..=100).map(|n| backup(n, n as u64 * 10, 1)).collect();
+        backups.push(backup(101, 5, 100));
+        backups.push(backup(102, 5, 170));
+        backups.push(backup(103, 5, 200));
+
+        let stats = Stats::compute(&backups, 180, now);
+        assert_eq!(stats.backups, 103);
+        assert_eq!(stats.total_bytes, 50_500 + 15);
+        assert_eq!(stats.average_bytes, 490);
+        assert_eq!(stats.size_percentiles.p50, 490);
+        assert_eq!(stats.size_percentiles.p90, 900);
+        assert_eq!(stats.size_percentiles.p99, 990);
+        assert_eq!(stats.size_percentiles.max, 1000);
+        let histogram: Vec<_> = stats
+            .age_histogram
+            .iter()
+            .map(|bucket| (bucket.min_days, bucket.max_days, bucket.backups))
+            .collect();
+        assert_eq!(
+            histogram,
+            vec![
+                (0, Some(45), 100),
+                (45, Some(90), 0),
+                (90, Some(135), 1),
+                (135, Some(180), 1),
+                (180, None, 1),
+            ]
+        );
+        assert_eq!(stats.near_expiry.backups, 1);
+        assert_eq!(stats.near_expiry.soonest[0].backup_id, backups[101].0);
+        assert_eq!(stats.near_expiry.soonest[0].expires_in_days, 10);
+        assert_eq!(stats.largest.len(), 10);
+        assert_eq!(stats.largest[0].size, 1000);
+        assert_eq!(stats.largest[9].size, 910);
+    }
+}
@@ -0,0 +1,475 @@
+//! Encryption of backups at rest.
+//!
+//! Backups are encrypted with AES-256-GCM in chunks, so that they can be
+//! streamed and read partially. An encrypted backup file consists of
+//!
+//! - a header with the magic bytes `\0SKRENC\n`, the format version, the id
+//!   of the key and a random salt,
+//! - the padded backup in chunks of 64 KiB, each followed by its tag,
+//! - a trailer with the encrypted length of the backup.
+//!
+//! Every file is encrypted with its own key, derived from the configured key
+//! and the salt with HMAC-SHA256. The nonce of every chunk is made of the
+//! index of the chunk and a flag that marks the trailer, so that chunks cannot
+//! be reordered or dropped, and nonces are never reused with the same key. The
+//! header is authenticated along with every chunk. Backups are padded to a
+//! multiple of 16 KiB, so that the file size only reveals the approximate size
+//! of a backup.
+//!
+//! Encrypted files are recognized by their header alone, so that they stay
+//! encrypted when the backup directory is copied. Threema Safe backups are
+//! encrypted by the app already, so a plaintext backup only starts with the
+//! magic bytes if it was crafted to, and such a backup is never served.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    ops::Range,
+    path::Path,
+};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use anyhow::{anyhow, bail, Context};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use super::hex;
+
+/// The size of the plaintext in every chunk but the last.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Backups are padded to a multiple of this size.
+const PADDING_SIZE: u64 = 16 * 1024;
+const MAGIC: &[u8; 8] = b"\0SKRENC\n";
+const FORMAT_VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 8;
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const KEY_ID_OFFSET: usize = MAGIC.len() + 1;
+pub const HEADER_LEN: usize = KEY_ID_OFFSET + KEY_ID_LEN + SALT_LEN;
+pub const TRAILER_LEN: usize = 8 + TAG_LEN;
+
+/// A key for encrypting backups.
+#[derive(Clone)]
+pub struct Key {
+    /// The start of the SHA-256 hash of the key, stored in the header
+    id: [u8; KEY_ID_LEN],
+    key: [u8; 32],
+}
+
+impl Key {
+    fn from_hex(line: &str) -> anyhow::Result<Self> {
+        if line.len() != 64 || !line.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Keys must consist of 64 hex characters");
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&line[2 * i..2 * i + 2], 16)?;
+        }
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&Sha256::digest(key)[..KEY_ID_LEN]);
+        Ok(Self { id, key })
+    }
+
+    /// Return the id of the key as hex string.
+    pub fn id(&self) -> String {
+        hex(&self.id)
+    }
+}
+
+// Never print the key itself
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Key").field("id", &self.id()).finish()
+    }
+}
+
+/// The keys from a key file.
+///
+/// The first key is the current key, which is used to encrypt backups. The
+/// other keys are only used to decrypt backups that were encrypted before
+/// the keys were rotated.
+#[derive(Debug, Clone)]
+pub struct Keys(Vec<Key>);
+
+impl Keys {
+    /// Read the keys from a key file.
+    ///
+    /// Every line holds a key of 64 hex characters. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read key file {:?}", path))?;
+        Self::parse(&contents).with_context(|| format!("Invalid key file {:?}", path))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let keys = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Key::from_hex)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            bail!("No key found");
+        }
+        Ok(Self(keys))
+    }
+
+    /// Return the key for encrypting backups.
+    pub fn current(&self) -> &Key {
+        &self.0[0]
+    }
+
+    fn find(&self, id: &[u8]) -> Option<&Key> {
+        self.0.iter().find(|key| key.id == id)
+    }
+}
+
+/// Return whether a file starts with the magic bytes of an encrypted backup.
+///
+/// Files of an unknown format version count as encrypted as well, so that
+/// they are never served as they are.
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+/// Return the id of the key an encrypted backup was encrypted with.
+pub fn key_id(header: &[u8]) -> String {
+    hex(&header[KEY_ID_OFFSET..KEY_ID_OFFSET + KEY_ID_LEN])
+}
+
+/// Derive the key of a single file from the salt in its header.
+fn file_key(key: &Key, header: &[u8]) -> [u8; 32] {
+    let salt = &header[HEADER_LEN - SALT_LEN..HEADER_LEN];
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&key.key).expect("HMAC accepts keys of any size");
+    mac.update(b"sekursranko file key");
+    mac.update(salt);
+    mac.finalize().into_bytes().into()
+}
+
+fn nonce(index: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..4].copy_from_slice(&index.to_be_bytes());
+    nonce[4] = u8::from(last);
+    nonce
+}
+
+/// Encrypts a backup while it is uploaded.
+pub struct Encryptor {
+    /// The cipher with the key of the file
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    /// Plaintext that does not fill a chunk yet
+    buffer: Vec<u8>,
+    index: u32,
+    len: u64,
+}
+
+impl Encryptor {
+    pub fn new(key: &Key) -> anyhow::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = FORMAT_VERSION;
+        header[KEY_ID_OFFSET..KEY_ID_OFFSET + KEY_ID_LEN].copy_from_slice(&key.id);
+        OsRng
+            .try_fill_bytes(&mut header[KEY_ID_OFFSET + KEY_ID_LEN..])
+            .context("Could not generate salt")?;
+        Ok(Self {
+            cipher: Aes256Gcm::new(&file_key(key, &header).into()),
+            header,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            index: 0,
+            len: 0,
+        })
+    }
+
+    /// Return the header, which must be written before any chunk.
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Add plaintext and return the encrypted chunks that are complete.
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.len += data.len() as u64;
+        self.buffer.extend_from_slice(data);
+        let mut sealed = vec![];
+        while self.buffer.len() >= CHUNK_SIZE {
+            let chunk: Vec<u8> = self.buffer.drain(..CHUNK_SIZE).collect();
+            sealed.extend(self.seal(&chunk, false)?);
+        }
+        Ok(sealed)
+    }
+
+    /// Pad the backup and return the remaining chunks along with the
+    /// trailer.
+    pub fn finish(mut self) -> anyhow::Result<Vec<u8>> {
+        let padded_len = (self.len / PADDING_SIZE + 1) * PADDING_SIZE;
+        let padding = (padded_len - self.len) as usize;
+        self.buffer.resize(self.buffer.len() + padding, 0);
+        let mut sealed = vec![];
+        while !self.buffer.is_empty() {
+            let end = self.buffer.len().min(CHUNK_SIZE);
+            let chunk: Vec<u8> = self.buffer.drain(..end).collect();
+            sealed.extend(self.seal(&chunk, false)?);
+        }
+        sealed.extend(self.seal(&self.len.to_le_bytes(), true)?);
+        Ok(sealed)
+    }
+
+    fn seal(&mut self, data: &[u8], last: bool) -> anyhow::Result<Vec<u8>> {
+        let payload = Payload {
+            msg: data,
+            aad: &self.header,
+        };
+        // The tag is appended to the ciphertext
+        let sealed = self
+            .cipher
+            .encrypt(&Nonce::from(nonce(self.index, last)), payload)
+            .map_err(|_| anyhow!("Could not encrypt backup"))?;
+        self.index = self.index.checked_add(1).context("Backup is too large")?;
+        Ok(sealed)
+    }
+}
+
+/// Decrypts the chunks of an encrypted backup file.
+pub struct Decryptor {
+    /// The cipher with the key of the file
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    /// The length of the backup without padding
+    len: u64,
+    /// The length of all chunks in the file
+    chunks_len: u64,
+}
+
+impl Decryptor {
+    /// Create a decryptor from the header and the trailer of a backup file
+    /// of the given size.
+    ///
+    /// This checks that the file is complete and was encrypted with one of
+    /// the keys.
+    pub fn new(keys: &Keys, header: &[u8], trailer: &[u8], file_size: u64) -> anyhow::Result<Self> {
+        if header.len() < HEADER_LEN || !is_encrypted(header) {
+            bail!("Not an encrypted backup");
+        }
+        if header[MAGIC.len()] != FORMAT_VERSION {
+            bail!(
+                "Unsupported encrypted backup version {}",
+                header[MAGIC.len()]
+            );
+        }
+        let key = keys
+            .find(&header[KEY_ID_OFFSET..KEY_ID_OFFSET + KEY_ID_LEN])
+            .with_context(|| format!("Backup was encrypted with unknown key {}", key_id(header)))?;
+        let chunks_len = file_size
+            .checked_sub((HEADER_LEN + TRAILER_LEN) as u64)
+            .context("Encrypted backup is truncated")?;
+        let mut decryptor = Self {
+            cipher: Aes256Gcm::new(&file_key(key, header).into()),
+            header: header[..HEADER_LEN]
+                .try_into()
+                .expect("Invalid header length"),
+            len: 0,
+            chunks_len,
+        };
+        let sealed_len = (CHUNK_SIZE + TAG_LEN) as u64;
+        let rest = chunks_len % sealed_len;
+        if rest > 0 && rest <= TAG_LEN as u64 {
+            bail!("Encrypted backup is truncated");
+        }
+        let chunks = u32::try_from(chunks_len / sealed_len + u64::from(rest > 0))
+            .context("Encrypted backup is too large")?;
+        let len = decryptor.open(chunks, true, trailer)?;
+        let len = u64::from_le_bytes(
+            len.try_into()
+                .map_err(|_| anyhow!("Invalid encrypted backup length"))?,
+        );
+        if len > chunks_len - u64::from(chunks) * TAG_LEN as u64 {
+            bail!("Invalid encrypted backup length");
+        }
+        decryptor.len = len;
+        Ok(decryptor)
+    }
+
+    /// Return the length of the backup.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Return the number of chunks that contain backup data, as opposed to
+    /// padding only.
+    pub fn data_chunks(&self) -> u32 {
+        self.len.div_ceil(CHUNK_SIZE as u64) as u32
+    }
+
+    /// Return the position of an encrypted chunk in the file.
+    pub fn chunk_range(&self, index: u32) -> Range<u64> {
+        let sealed_len = (CHUNK_SIZE + TAG_LEN) as u64;
+        let start = HEADER_LEN as u64 + u64::from(index) * sealed_len;
+        let end = (start + sealed_len).min(HEADER_LEN as u64 + self.chunks_len);
+        start..end
+    }
+
+    /// Decrypt a chunk and return the backup data it contains, without
+    /// padding.
+    pub fn decrypt_chunk(&self, index: u32, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut chunk = self.open(index, false, sealed)?;
+        let start = u64::from(index) * CHUNK_SIZE as u64;
+        let data_len = self.len.saturating_sub(start).min(chunk.len() as u64);
+        chunk.truncate(data_len as usize);
+        Ok(chunk)
+    }
+
+    fn open(&self, index: u32, last: bool, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < TAG_LEN {
+            bail!("Encrypted backup is truncated");
+        }
+        let payload = Payload {
+            msg: sealed,
+            aad: &self.header,
+        };
+        self.cipher
+            .decrypt(&Nonce::from(nonce(index, last)), payload)
+            .map_err(|_| anyhow!("Could not decrypt backup, it is corrupted"))
+    }
+}
+
+// Never print the file key
+impl fmt::Debug for Decryptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Decryptor")
+            .field("key_id", &key_id(&self.header))
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Encrypt a whole backup.
+pub fn encrypt(key: &Key, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encryptor = Encryptor::new(key)?;
+    let mut encrypted = encryptor.header().to_vec();
+    encrypted.extend(encryptor.update(data)?);
+    encrypted.extend(encryptor.finish()?);
+    Ok(encrypted)
+}
+
+/// Decrypt a whole backup.
+pub fn decrypt(keys: &Keys, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.len() < HEADER_LEN + TRAILER_LEN {
+        bail!("Encrypted backup is truncated");
+    }
+    let decryptor = Decryptor::new(
+        keys,
+        &data[..HEADER_LEN],
+        &data[data.len() - TRAILER_LEN..],
+        data.len() as u64,
+    )?;
+    let mut decrypted = Vec::with_capacity(decryptor.len() as usize);
+    for index in 0..decryptor.data_chunks() {
+        let range = decryptor.chunk_range(index);
+        let sealed = &data[range.start as usize..range.end as usize];
+        decrypted.extend(decryptor.decrypt_chunk(index, sealed)?);
+    }
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = Keys::parse(&format!("# Current key\n{}\n\n{}\n", KEY_2, KEY_1)).unwrap();
+        assert_eq!(keys.0.len(), 2);
+        assert_eq!(keys.current().key[0], 0x1f);
+        assert!(!format!("{:?}", keys).contains(KEY_2));
+
+        assert!(Keys::parse("").is_err());
+        assert!(Keys::parse("# No key\n").is_err());
+        assert!(Keys::parse(&KEY_1[..62]).is_err());
+        assert!(Keys::parse(&format!("{}zz", &KEY_1[..62])).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let keys = Keys::parse(KEY_1).unwrap();
+        for len in [0, 1, 1000, CHUNK_SIZE - 1, CHUNK_SIZE, 3 * CHUNK_SIZE + 7] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(keys.current(), &data).unwrap();
+            assert!(is_encrypted(&encrypted));
+            // Only the padded size is revealed
+            let chunks_len = encrypted.len() - HEADER_LEN - TRAILER_LEN;
+            let plaintext_len = chunks_len - (len / CHUNK_SIZE + 1) * TAG_LEN;
+            assert_eq!(plaintext_len as u64 % PADDING_SIZE, 0);
+            assert!(plaintext_len > len);
+            assert_eq!(decrypt(&keys, &encrypted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_tampered() {
+        let keys = Keys::parse(KEY_1).unwrap();
+        let data = vec![42; 2 * CHUNK_SIZE];
+        let encrypted = encrypt(keys.current(), &data).unwrap();
+
+        // Unknown key
+        let other_keys = Keys::parse(KEY_2).unwrap();
+        assert!(decrypt(&other_keys, &encrypted).is_err());
+
+        // Modified chunk
+        let mut modified = encrypted.clone();
+        modified[HEADER_LEN + 10] ^= 1;
+        assert!(decrypt(&keys, &modified).is_err());
+
+        // Modified header
+        let mut modified = encrypted.clone();
+        modified[HEADER_LEN - 1] ^= 1;
+        assert!(decrypt(&keys, &modified).is_err());
+
+        // Dropped chunk
+        let sealed_len = CHUNK_SIZE + TAG_LEN;
+        let mut truncated = encrypted[..HEADER_LEN].to_vec();
+        truncated.extend(&encrypted[HEADER_LEN + sealed_len..]);
+        assert!(decrypt(&keys, &truncated).is_err());
+
+        // Truncated file
+        assert!(decrypt(&keys, &encrypted[..encrypted.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old_keys = Keys::parse(KEY_1).unwrap();
+        let encrypted = encrypt(old_keys.current(), b"sekur").unwrap();
+        assert_eq!(key_id(&encrypted), old_keys.current().id());
+
+        let keys = Keys::parse(&format!("{}\n{}", KEY_2, KEY_1)).unwrap();
+        assert_ne!(key_id(&encrypted), keys.current().id());
+        assert_eq!(decrypt(&keys, &encrypted).unwrap(), b"sekur");
+    }
+
+    #[test]
+    fn test_file_keys() {
+        let keys = Keys::parse(KEY_1).unwrap();
+        let first = encrypt(keys.current(), b"sekur").unwrap();
+        let second = encrypt(keys.current(), b"sekur").unwrap();
+        assert_ne!(first[..HEADER_LEN], second[..HEADER_LEN]);
+        assert_ne!(
+            file_key(keys.current(), &first),
+            file_key(keys.current(), &second)
+        );
+        assert_ne!(first[HEADER_LEN..], second[HEADER_LEN..]);
+
+        // The salt is authenticated and determines the key
+        let mut modified = first.clone();
+        modified[HEADER_LEN - SALT_LEN] ^= 1;
+        assert!(decrypt(&keys, &modified).is_err());
+    }
+}
@@ -0,0 +1,1595 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    io::SeekFrom,
+    io::{Error as IoError, ErrorKind},
+    ops::Range,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use futures::{
+    future::BoxFuture,
+    stream::{self, Stream},
+    StreamExt,
+};
+use hyper::{body::Bytes, Body};
+use log::{debug, trace, warn};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use super::{
+    encryption::{self, Decryptor, Encryptor, Key, Keys, CHUNK_SIZE, HEADER_LEN, TRAILER_LEN},
+    hex, slice_body, Backup, BackupMetadata, BackupStore, BackupVersion, TrashedBackup,
+};
+use crate::{config::FilesystemConfig, handlers::backup_id_valid, metrics::metrics};
+
+/// A backup store that keeps every backup as a file in the backup directory.
+///
+/// In the sharded layout, backups are stored in two levels of subdirectories
+/// named after the first four characters of the backup id (e.g.
+/// `01/23/0123...`). Backups that are still in the flat layout are found as
+/// well, so that the server can keep running while the backup directory is
+/// migrated with [`FilesystemStore::migrate_to_sharded`].
+///
+/// Previous versions of a backup are kept as hard links named after their
+/// version number in `versions/<backup id>/`. Deleted backups are moved to
+/// `trash/<backup id>/`, along with their versions and the time of deletion.
+///
+/// If keys are configured with [`FilesystemStore::with_encryption`], all
+/// files are encrypted at rest. Files without the header of an encrypted file
+/// are read as they are, until they are rewritten by
+/// [`FilesystemStore::reencrypt`]. The sizes in a listing of all
+/// backups are the sizes of the files.
+#[derive(Debug, Clone)]
+pub struct FilesystemStore {
+    backup_dir: PathBuf,
+    sharded: bool,
+    fsync: bool,
+    keep_versions: u32,
+    keys: Option<Arc<Keys>>,
+    temp_files: TempFiles,
+    etags: EtagCache,
+}
+
+/// The content hashes of backups by backup id, along with the size and
+/// modification time of the backup file they were computed for.
+type EtagCache = Arc<Mutex<HashMap<String, (u64, SystemTime, String)>>>;
+
+/// The temporary files of uploads that are in progress.
+type TempFiles = Arc<Mutex<HashSet<PathBuf>>>;
+
+/// A temporary upload file that is removed when dropped, unless it was
+/// moved to its final location.
+///
+/// This makes sure that no temporary files are left behind when an upload
+/// fails or the request is aborted.
+struct TempFile {
+    path: PathBuf,
+    temp_files: TempFiles,
+    persisted: bool,
+}
+
+impl TempFile {
+    fn new(path: PathBuf, temp_files: &TempFiles) -> Self {
+        temp_files
+            .lock()
+            .expect("Temp files lock is poisoned")
+            .insert(path.clone());
+        Self {
+            path,
+            temp_files: temp_files.clone(),
+            persisted: false,
+        }
+    }
+
+    /// Move the temporary file to its final location.
+    async fn persist(mut self, path: &Path) -> Result<(), IoError> {
+        fs::rename(&self.path, path).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        self.temp_files
+            .lock()
+            .expect("Temp files lock is poisoned")
+            .remove(&self.path);
+        if self.persisted {
+            return;
+        }
+        debug!("Removing temporary upload {:?}", self.path);
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not remove temporary upload {:?}: {}", self.path, e),
+        }
+    }
+}
+
+/// An open backup file, which is decrypted while reading if it is
+/// encrypted.
+struct BackupReader {
+    file: fs::File,
+    decryptor: Option<Decryptor>,
+    /// The next encrypted chunk
+    index: u32,
+    /// The number of bytes to skip at the start of the next encrypted chunk
+    skip: usize,
+}
+
+impl BackupReader {
+    /// Return the length of the backup, given the size of the file.
+    fn len(&self, file_size: u64) -> u64 {
+        self.decryptor
+            .as_ref()
+            .map_or(file_size, |decryptor| decryptor.len())
+    }
+
+    /// Continue reading at the given offset of the backup.
+    async fn seek(&mut self, offset: u64) -> anyhow::Result<()> {
+        let position = match &self.decryptor {
+            Some(decryptor) => {
+                self.index = (offset / CHUNK_SIZE as u64) as u32;
+                self.skip = (offset % CHUNK_SIZE as u64) as usize;
+                decryptor.chunk_range(self.index).start
+            }
+            None => offset,
+        };
+        self.file
+            .seek(SeekFrom::Start(position))
+            .await
+            .context("Could not seek in backup file")?;
+        Ok(())
+    }
+
+    /// Read the next chunk of the backup, or `None` at the end.
+    async fn next_chunk(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let decryptor = match &self.decryptor {
+            Some(decryptor) => decryptor,
+            None => {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let read = self
+                    .file
+                    .read(&mut chunk)
+                    .await
+                    .context("Could not read backup")?;
+                chunk.truncate(read);
+                return Ok(Some(chunk).filter(|chunk| !chunk.is_empty()));
+            }
+        };
+        if self.index >= decryptor.data_chunks() {
+            return Ok(None);
+        }
+        let range = decryptor.chunk_range(self.index);
+        let mut sealed = vec![0; (range.end - range.start) as usize];
+        self.file
+            .read_exact(&mut sealed)
+            .await
+            .context("Could not read backup")?;
+        let mut chunk = decryptor.decrypt_chunk(self.index, &sealed)?;
+        chunk.drain(..self.skip.min(chunk.len()));
+        self.index += 1;
+        self.skip = 0;
+        Ok(Some(chunk))
+    }
+
+    /// Stream the rest of the backup.
+    fn into_stream(self) -> impl Stream<Item = Result<Bytes, IoError>> {
+        stream::try_unfold(self, |mut reader| async move {
+            match reader.next_chunk().await {
+                Ok(Some(chunk)) => Ok(Some((Bytes::from(chunk), reader))),
+                Ok(None) => Ok(None),
+                Err(e) => Err(IoError::other(format!("{:#}", e))),
+            }
+        })
+    }
+}
+
+impl FilesystemStore {
+    pub fn new(backup_dir: &Path, config: &FilesystemConfig) -> Self {
+        Self {
+            backup_dir: backup_dir.to_path_buf(),
+            sharded: config.sharded.unwrap_or(false),
+            fsync: config.fsync.unwrap_or(false),
+            keep_versions: 0,
+            keys: None,
+            temp_files: TempFiles::default(),
+            etags: EtagCache::default(),
+        }
+    }
+
+    /// Keep the given number of previous versions of every backup.
+    pub fn with_versions(mut self, keep_versions: u32) -> Self {
+        self.keep_versions = keep_versions;
+        self
+    }
+
+    /// Encrypt all backups that are written with the current key.
+    pub fn with_encryption(mut self, keys: Arc<Keys>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Return the path of a backup in the flat layout.
+    fn flat_path(&self, backup_id: &str) -> PathBuf {
+        self.backup_dir.join(backup_id)
+    }
+
+    /// Return the path where a backup is stored in the configured layout.
+    fn backup_path(&self, backup_id: &str) -> PathBuf {
+        if self.sharded {
+            self.backup_dir
+                .join(&backup_id[0..2])
+                .join(&backup_id[2..4])
+                .join(backup_id)
+        } else {
+            self.flat_path(backup_id)
+        }
+    }
+
+    /// Return the directory with the previous versions of a backup.
+    fn versions_dir(&self, backup_id: &str) -> PathBuf {
+        self.backup_dir.join("versions").join(backup_id)
+    }
+
+    /// Return the number, path and metadata of all previous versions of a
+    /// backup, oldest first.
+    async fn read_versions(
+        &self,
+        backup_id: &str,
+    ) -> anyhow::Result<Vec<(u32, PathBuf, Metadata)>> {
+        read_versions_dir(&self.versions_dir(backup_id)).await
+    }
+
+    /// Keep the current file of a backup as a new previous version.
+    ///
+    /// Return the path of the new version, or `None` if versioning is
+    /// disabled.
+    async fn archive(&self, backup_id: &str, path: &Path) -> anyhow::Result<Option<PathBuf>> {
+        if self.keep_versions == 0 {
+            return Ok(None);
+        }
+        let version = match self.read_versions(backup_id).await?.last() {
+            Some((version, _, _)) => version.checked_add(1).context("No version numbers left")?,
+            None => 1,
+        };
+        let version_path = self.versions_dir(backup_id).join(version.to_string());
+        create_parent_dirs(&version_path).await?;
+        // The data is not copied, the upload only replaces the directory
+        // entry of the backup
+        fs::hard_link(path, &version_path)
+            .await
+            .with_context(|| format!("Could not create version {:?}", version_path))?;
+        if self.fsync {
+            sync_dir(version_path.parent().expect("Version path without parent"))
+                .await
+                .context("Could not sync versions directory")?;
+        }
+        trace!("Kept version {} of backup {}", version, backup_id);
+        Ok(Some(version_path))
+    }
+
+    /// Remove the oldest previous versions of a backup that exceed the
+    /// configured number of versions.
+    async fn prune_versions(&self, backup_id: &str) -> anyhow::Result<()> {
+        let versions = self.read_versions(backup_id).await?;
+        let excess = versions.len().saturating_sub(self.keep_versions as usize);
+        for (_, path, _) in versions.into_iter().take(excess) {
+            fs::remove_file(&path)
+                .await
+                .with_context(|| format!("Could not remove version {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    /// Return the trash directory of a backup.
+    fn trash_dir(&self, backup_id: &str) -> PathBuf {
+        self.backup_dir.join("trash").join(backup_id)
+    }
+
+    /// Return the id, directory and deletion time of all backups in the
+    /// trash, along with the metadata of the backup.
+    ///
+    /// The metadata is `None` if the backup was not completely moved to the
+    /// trash, e.g. because of a crash.
+    async fn read_trash(
+        &self,
+    ) -> anyhow::Result<Vec<(String, PathBuf, SystemTime, Option<BackupMetadata>)>> {
+        let dir = self.backup_dir.join("trash");
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).with_context(|| format!("Could not read directory {:?}", dir)),
+        };
+        let mut trash = vec![];
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("Could not read trash directory entry")?
+        {
+            let backup_id = match entry.file_name().into_string() {
+                Ok(name) if backup_id_valid(&name) => name,
+                _ => continue,
+            };
+            let path = entry.path();
+            let deleted = match fs::read_to_string(path.join("deleted")).await {
+                Ok(secs) => secs
+                    .trim()
+                    .parse()
+                    .ok()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Could not read deletion time {:?}", path))
+                }
+            };
+            let deleted = match deleted {
+                Some(deleted) => deleted,
+                None => entry
+                    .metadata()
+                    .await
+                    .and_then(|metadata| metadata.modified())
+                    .context("Could not read trash directory metadata")?,
+            };
+            let backup_path = path.join("backup");
+            let metadata = match metadata_if_exists(&backup_path).await? {
+                Some(metadata) if metadata.is_file() => Some(BackupMetadata {
+                    size: self.backup_size(&backup_path, &metadata).await?,
+                    ..backup_metadata(&metadata)?
+                }),
+                _ => None,
+            };
+            trash.push((backup_id, path, deleted, metadata));
+        }
+        Ok(trash)
+    }
+
+    /// Store a backup, keeping the previous backup as a version.
+    ///
+    /// Return true if an existing backup was updated.
+    async fn store(&self, backup_id: &str, body: Body) -> anyhow::Result<bool> {
+        let existing_path = match self.find(backup_id).await? {
+            Some((path, metadata)) if !metadata.is_file() => bail!(
+                "Tried to upload to a backup path that exists but is not a file: {:?}",
+                path
+            ),
+            Some((path, _)) => Some(path),
+            None => None,
+        };
+        let backup_path = self.backup_path(backup_id);
+        if self.sharded {
+            create_parent_dirs(&backup_path).await?;
+        }
+        let archived = match &existing_path {
+            Some(path) => self.archive(backup_id, path).await?,
+            None => None,
+        };
+        let key = self.keys.as_ref().map(|keys| keys.current());
+        let written = write_backup(
+            body,
+            backup_id,
+            &backup_path,
+            &self.temp_files,
+            self.fsync,
+            key,
+        )
+        .await;
+        let (updated, etag) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                // The backup was not replaced, so it must not be kept twice
+                if let Some(path) = archived {
+                    if let Err(e) = fs::remove_file(&path).await {
+                        warn!("Could not remove version {:?}: {}", path, e);
+                    }
+                }
+                return Err(e);
+            }
+        };
+        if let Some(metadata) = metadata_if_exists(&backup_path).await? {
+            let metadata = backup_metadata(&metadata)?;
+            self.cache_etag(backup_id, metadata.size, metadata.modified, &etag);
+        }
+        self.prune_versions(backup_id).await?;
+
+        // Remove the previous backup if it has not been migrated to the sharded layout yet
+        match existing_path {
+            Some(path) if path != backup_path => {
+                // The migration may have removed it already
+                remove_file_if_exists(&path).await?;
+                Ok(true)
+            }
+            _ => Ok(updated),
+        }
+    }
+
+    /// Return the path and metadata of an existing backup path.
+    ///
+    /// Note that the path is not necessarily a regular file.
+    async fn find(&self, backup_id: &str) -> anyhow::Result<Option<(PathBuf, Metadata)>> {
+        let backup_path = self.backup_path(backup_id);
+        if let Some(metadata) = metadata_if_exists(&backup_path).await? {
+            return Ok(Some((backup_path, metadata)));
+        }
+        if self.sharded {
+            let flat_path = self.flat_path(backup_id);
+            if let Some(metadata) = metadata_if_exists(&flat_path).await? {
+                return Ok(Some((flat_path, metadata)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Open a backup file and return it along with its metadata.
+    ///
+    /// The ETag is only included if it is cached.
+    async fn open(
+        &self,
+        backup_id: &str,
+    ) -> anyhow::Result<Option<(BackupReader, BackupMetadata)>> {
+        let (reader, mut metadata) = match self.open_file(backup_id).await? {
+            Some(backup) => backup,
+            None => return Ok(None),
+        };
+        metadata.etag = self.cached_etag(backup_id, &metadata);
+        metadata.size = reader.len(metadata.size);
+        Ok(Some((reader, metadata)))
+    }
+
+    /// Open a backup file and return it along with the metadata of the file.
+    ///
+    /// The size is the size of the file, which is larger than the backup if
+    /// the file is encrypted.
+    async fn open_file(
+        &self,
+        backup_id: &str,
+    ) -> anyhow::Result<Option<(BackupReader, BackupMetadata)>> {
+        let backup_path = match self.find(backup_id).await? {
+            Some((path, metadata)) if metadata.is_file() => path,
+            _ => return Ok(None),
+        };
+        let file = match fs::File::open(&backup_path).await {
+            Ok(file) => file,
+            // The backup was deleted in the meantime
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not open file {:?}", backup_path))
+            }
+        };
+        // Take the metadata from the open file, in case the backup is
+        // replaced in the meantime
+        let metadata = file
+            .metadata()
+            .await
+            .with_context(|| format!("Could not read metadata of {:?}", backup_path))?;
+        let metadata = backup_metadata(&metadata)?;
+        let reader = self.reader(file, metadata.size).await?;
+        Ok(Some((reader, metadata)))
+    }
+
+    /// Create a reader for an open file, which decrypts the file if it is
+    /// encrypted.
+    async fn reader(&self, mut file: fs::File, file_size: u64) -> anyhow::Result<BackupReader> {
+        let mut decryptor = None;
+        let mut header = vec![0; HEADER_LEN.min(file_size as usize)];
+        file.read_exact(&mut header)
+            .await
+            .context("Could not read backup header")?;
+        if encryption::is_encrypted(&header) {
+            let keys = match &self.keys {
+                Some(keys) => keys,
+                None => bail!("Backup is encrypted, but no encryption_key_file is configured"),
+            };
+            if file_size < (HEADER_LEN + TRAILER_LEN) as u64 {
+                bail!("Encrypted backup is truncated");
+            }
+            let mut trailer = [0; TRAILER_LEN];
+            file.seek(SeekFrom::Start(file_size - TRAILER_LEN as u64))
+                .await
+                .context("Could not seek in backup file")?;
+            file.read_exact(&mut trailer)
+                .await
+                .context("Could not read backup trailer")?;
+            decryptor = Some(Decryptor::new(keys, &header, &trailer, file_size)?);
+        }
+        let mut reader = BackupReader {
+            file,
+            decryptor,
+            index: 0,
+            skip: 0,
+        };
+        reader.seek(0).await?;
+        Ok(reader)
+    }
+
+    /// Return the size of a backup, version or backup in the trash.
+    async fn backup_size(&self, path: &Path, metadata: &Metadata) -> anyhow::Result<u64> {
+        let file = fs::File::open(path)
+            .await
+            .with_context(|| format!("Could not open file {:?}", path))?;
+        Ok(self.reader(file, metadata.len()).await?.len(metadata.len()))
+    }
+
+    /// Return the cached content hash of a backup file with the given file
+    /// metadata.
+    fn cached_etag(&self, backup_id: &str, metadata: &BackupMetadata) -> Option<String> {
+        match self
+            .etags
+            .lock()
+            .expect("ETag cache lock is poisoned")
+            .get(backup_id)
+        {
+            Some((size, modified, etag))
+                if *size == metadata.size && *modified == metadata.modified =>
+            {
+                Some(etag.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the content hash of an open backup file.
+    ///
+    /// The hash is computed while uploading, so the file only needs to be
+    /// read if the backup was stored by a previous version or process.
+    async fn compute_etag(
+        &self,
+        backup_id: &str,
+        reader: &mut BackupReader,
+        metadata: &BackupMetadata,
+    ) -> anyhow::Result<String> {
+        if let Some(etag) = self.cached_etag(backup_id, metadata) {
+            return Ok(etag);
+        }
+
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = reader.next_chunk().await? {
+            hasher.update(&chunk);
+        }
+        reader.seek(0).await.context("Could not rewind backup")?;
+        let etag = hex(&hasher.finalize());
+        self.cache_etag(backup_id, metadata.size, metadata.modified, &etag);
+        Ok(etag)
+    }
+
+    fn cache_etag(&self, backup_id: &str, size: u64, modified: SystemTime, etag: &str) {
+        self.etags
+            .lock()
+            .expect("ETag cache lock is poisoned")
+            .insert(backup_id.to_string(), (size, modified, etag.to_string()));
+    }
+
+    /// Move all backups from the flat layout into the sharded layout.
+    ///
+    /// Every backup is hard linked into the sharded layout before the flat
+    /// file is removed, so a backup that is uploaded while the migration is
+    /// running is never overwritten and the server can keep running. Flat
+    /// files whose backup already exists in the sharded layout are left in
+    /// place. If the migration is interrupted, it can simply be started
+    /// again. Return the number of migrated backups.
+    pub async fn migrate_to_sharded(&self) -> anyhow::Result<usize> {
+        if !self.sharded {
+            bail!("The sharded layout must be enabled in the config before migrating");
+        }
+        let mut migrated = 0;
+        let mut flat_backups = vec![];
+        scan_dir(&self.backup_dir, "", &mut flat_backups).await?;
+        for (backup_id, _) in flat_backups {
+            let flat_path = self.flat_path(&backup_id);
+            let backup_path = self.backup_path(&backup_id);
+            create_parent_dirs(&backup_path).await?;
+            // Unlike a rename, a hard link never replaces a backup that was
+            // uploaded to the sharded layout since the migration started
+            match fs::hard_link(&flat_path, &backup_path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    warn!(
+                        "Backup {} exists in both layouts, keeping {:?}",
+                        backup_id, flat_path
+                    );
+                    continue;
+                }
+                // The backup was deleted or uploaded again in the meantime
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Could not link {:?} to {:?}", flat_path, backup_path)
+                    })
+                }
+            }
+            remove_file_if_exists(&flat_path).await?;
+            debug!("Migrated backup {}", backup_id);
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+
+    /// Rewrite all backups, previous versions and backups in the trash that
+    /// are not encrypted with the current key.
+    ///
+    /// Files that are not encrypted at all are encrypted as well. The
+    /// modification times are kept, so that the retention period of the
+    /// backups is not reset. Uploads are not locked out, so the server must
+    /// not be running. If the re-encryption is interrupted, it can simply be
+    /// started again. Return the number of rewritten files.
+    pub async fn reencrypt(&self) -> anyhow::Result<usize> {
+        let keys = match &self.keys {
+            Some(keys) => keys.clone(),
+            None => bail!("An encryption_key_file must be configured before re-encrypting"),
+        };
+
+        let mut paths = vec![];
+        for (backup_id, _) in self.list().await? {
+            if let Some((path, _)) = self.find(&backup_id).await? {
+                paths.push(path);
+            }
+        }
+        let versions_dir = self.backup_dir.join("versions");
+        if metadata_if_exists(&versions_dir).await?.is_some() {
+            let mut entries = fs::read_dir(&versions_dir)
+                .await
+                .with_context(|| format!("Could not read directory {:?}", versions_dir))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .context("Could not read versions directory entry")?
+            {
+                for (_, path, _) in read_versions_dir(&entry.path()).await? {
+                    paths.push(path);
+                }
+            }
+        }
+        for (_, dir, _, metadata) in self.read_trash().await? {
+            if metadata.is_some() {
+                paths.push(dir.join("backup"));
+            }
+            for (_, path, _) in read_versions_dir(&dir.join("versions")).await? {
+                paths.push(path);
+            }
+        }
+
+        let mut rewritten = 0;
+        for path in paths {
+            if self.reencrypt_file(&path, &keys).await? {
+                debug!("Re-encrypted {:?}", path);
+                rewritten += 1;
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// Rewrite a file with the current key, unless it is encrypted with it
+    /// already.
+    async fn reencrypt_file(&self, path: &Path, keys: &Keys) -> anyhow::Result<bool> {
+        let data = fs::read(path)
+            .await
+            .with_context(|| format!("Could not read {:?}", path))?;
+        let modified = fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Could not read modification time of {:?}", path))?;
+        let key = keys.current();
+        let data = if encryption::is_encrypted(&data) {
+            if data.len() >= HEADER_LEN && encryption::key_id(&data) == key.id() {
+                return Ok(false);
+            }
+            encryption::decrypt(keys, &data)
+                .with_context(|| format!("Could not decrypt {:?}", path))?
+        } else {
+            data
+        };
+        let encrypted = encryption::encrypt(key, &data)?;
+
+        let temp_path = temp_path(path);
+        let mut file = create_file(&temp_path)
+            .await
+            .context("Could not create temporary file")?;
+        let temp_file = TempFile::new(temp_path, &self.temp_files);
+        file.write_all(&encrypted)
+            .await
+            .context("Could not write temporary file")?;
+        let file = file.into_std().await;
+        file.set_modified(modified)
+            .context("Could not set modification time of temporary file")?;
+        if self.fsync {
+            file.sync_all().context("Could not sync temporary file")?;
+        }
+        drop(file);
+        temp_file
+            .persist(path)
+            .await
+            .with_context(|| format!("Could not replace {:?}", path))?;
+        if self.fsync {
+            let dir = path.parent().expect("Backup path without parent");
+            sync_dir(dir)
+                .await
+                .with_context(|| format!("Could not sync directory {:?}", dir))?;
+        }
+        Ok(true)
+    }
+}
+
+/// Return the number, path and metadata of all versions in a versions
+/// directory, oldest first.
+async fn read_versions_dir(dir: &Path) -> anyhow::Result<Vec<(u32, PathBuf, Metadata)>> {
+    let mut entries = match fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("Could not read directory {:?}", dir)),
+    };
+    let mut versions = vec![];
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context("Could not read versions directory entry")?
+    {
+        let version = match entry.file_name().to_str().map(str::parse) {
+            Some(Ok(version)) => version,
+            _ => continue,
+        };
+        let metadata = entry
+            .metadata()
+            .await
+            .context("Could not read version metadata")?;
+        if metadata.is_file() {
+            versions.push((version, entry.path(), metadata));
+        }
+    }
+    versions.sort_unstable_by_key(|(version, _, _)| *version);
+    Ok(versions)
+}
+
+/// Return the metadata of a path, or `None` if it does not exist.
+async fn metadata_if_exists(path: &Path) -> anyhow::Result<Option<Metadata>> {
+    match fs::metadata(path).await {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Could not read metadata of {:?}", path)),
+    }
+}
+
+async fn remove_file_if_exists(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Could not remove {:?}", path)),
+    }
+}
+
+fn backup_metadata(metadata: &Metadata) -> anyhow::Result<BackupMetadata> {
+    Ok(BackupMetadata {
+        size: metadata.len(),
+        // The modification time corresponds to the last upload
+        modified: metadata
+            .modified()
+            .context("Could not read backup modification time")?,
+        etag: None,
+    })
+}
+
+/// Collect all backup files in a directory.
+///
+/// Only backups starting with the shard prefix are considered.
+async fn scan_dir(
+    dir: &Path,
+    shard_prefix: &str,
+    backups: &mut Vec<(String, BackupMetadata)>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Could not read backup directory {:?}", dir))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context("Could not read backup directory entry")?
+    {
+        // Only consider backup files, skip temporary uploads and anything else
+        let backup_id = match entry.file_name().into_string() {
+            Ok(name) if backup_id_valid(&name) && name.starts_with(shard_prefix) => name,
+            _ => continue,
+        };
+        let metadata = entry
+            .metadata()
+            .await
+            .context("Could not read backup metadata")?;
+        if metadata.is_file() {
+            backups.push((backup_id, backup_metadata(&metadata)?));
+        }
+    }
+    Ok(())
+}
+
+/// Collect all temporary upload files in a directory.
+///
+/// Temporary files are named after the backup id with a random extension of
+/// 10 alphanumeric characters.
+async fn scan_temp_files(
+    dir: &Path,
+    temp_files: &mut Vec<(PathBuf, Metadata)>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Could not read backup directory {:?}", dir))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context("Could not read backup directory entry")?
+    {
+        let is_temp_file = match entry.file_name().to_str().and_then(|n| n.split_once('.')) {
+            Some((backup_id, ext)) => {
+                backup_id_valid(backup_id)
+                    && ext.len() == 10
+                    && ext.chars().all(|c| c.is_ascii_alphanumeric())
+            }
+            None => false,
+        };
+        if !is_temp_file {
+            continue;
+        }
+        let metadata = entry
+            .metadata()
+            .await
+            .context("Could not read temporary file metadata")?;
+        if metadata.is_file() {
+            temp_files.push((entry.path(), metadata));
+        }
+    }
+    Ok(())
+}
+
+/// Return the names of all shard subdirectories in a directory.
+async fn shard_dirs(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut shards = vec![];
+    let mut entries = fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Could not read backup directory {:?}", dir))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context("Could not read backup directory entry")?
+    {
+        let name = match entry.file_name().into_string() {
+            Ok(name) if name.len() == 2 && backup_id_valid(&name.repeat(32)) => name,
+            _ => continue,
+        };
+        if entry.file_type().await?.is_dir() {
+            shards.push(name);
+        }
+    }
+    Ok(shards)
+}
+
+/// Remove a directory and all of its contents, if it exists.
+async fn remove_dir_if_exists(dir: &Path) -> anyhow::Result<()> {
+    match fs::remove_dir_all(dir).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Could not remove directory {:?}", dir)),
+    }
+}
+
+// Create the parent directories of a path with permissions set to 0700.
+async fn create_parent_dirs(path: &Path) -> anyhow::Result<()> {
+    let parent = path.parent().expect("Backup path without parent");
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(parent)
+        .await
+        .with_context(|| format!("Could not create directory {:?}", parent))
+}
+
+// Create a file with permissions set to 0600.
+async fn create_file(path: &Path) -> Result<fs::File, IoError> {
+    let file = fs::File::create(path).await?;
+    let mut perms = file.metadata().await?.permissions();
+    perms.set_mode(0o600);
+    file.set_permissions(perms).await?;
+    Ok(file)
+}
+
+/// Return a path for a temporary file next to a file, with a random
+/// extension of 10 alphanumeric characters.
+fn temp_path(path: &Path) -> PathBuf {
+    let random_ext: String = {
+        let mut rng = rand::thread_rng();
+        std::iter::repeat(())
+            .map(|_| rng.sample(rand::distributions::Alphanumeric))
+            .map(char::from)
+            .take(10)
+            .collect()
+    };
+    path.with_extension(random_ext)
+}
+
+// Flush the entries of a directory to disk.
+async fn sync_dir(dir: &Path) -> Result<(), IoError> {
+    fs::File::open(dir).await?.sync_all().await
+}
+
+/// Store the backup to the file system.
+///
+/// Return true if an existing backup was updated, or false if a new backup
+/// was created, along with the content hash.
+async fn write_backup(
+    mut body: Body,
+    backup_id: &str,
+    backup_path: &Path,
+    temp_files: &TempFiles,
+    fsync: bool,
+    key: Option<&Key>,
+) -> anyhow::Result<(bool, String)> {
+    // The incoming stream will be written to a temporary file. This is done to prevent
+    // incomplete backups from being persisted.
+    let backup_path_dl = temp_path(backup_path);
+    trace!("Writing temporary upload to {:?}", backup_path_dl);
+    if backup_path_dl.exists() {
+        bail!(
+            "Random upload path \"{:?}\" already exists!",
+            backup_path_dl
+        );
+    }
+
+    // Create the empty download file to ensure correct permissions before
+    // writing the data
+    let mut backup_file_dl = create_file(&backup_path_dl)
+        .await
+        .context("Could not create temporary file")?;
+    let temp_file = TempFile::new(backup_path_dl.clone(), temp_files);
+
+    // Write data to temporary file, encrypting it if a key is configured
+    let mut hasher = Sha256::new();
+    let mut encryptor = key.map(Encryptor::new).transpose()?;
+    if let Some(encryptor) = &encryptor {
+        backup_file_dl
+            .write_all(encryptor.header())
+            .await
+            .context("Could not write header to temporary file")?;
+    }
+    while let Some(chunk_or_error) = body.next().await {
+        let chunk = chunk_or_error.context("Could not read body chunk")?;
+        hasher.update(&chunk);
+        let chunk = match &mut encryptor {
+            Some(encryptor) => encryptor.update(&chunk)?.into(),
+            None => chunk,
+        };
+        backup_file_dl
+            .write_all(&chunk)
+            .await
+            .context("Could not write chunk to temporary file")?
+    }
+    if let Some(encryptor) = encryptor {
+        backup_file_dl
+            .write_all(&encryptor.finish()?)
+            .await
+            .context("Could not write chunk to temporary file")?;
+    }
+    trace!("Wrote temp backup for {}", backup_id);
+
+    // Make sure that the data is on disk before it replaces the previous backup
+    if fsync {
+        let started = Instant::now();
+        backup_file_dl
+            .sync_all()
+            .await
+            .context("Could not sync temporary file")?;
+        metrics().fsync_file.observe(started.elapsed());
+    }
+    drop(backup_file_dl);
+
+    // Move temporary file to final location
+    let updated = backup_path.exists() && backup_path.is_file();
+    temp_file
+        .persist(backup_path)
+        .await
+        .context("Could not move temporary backup to final location")?;
+    trace!("Renamed: {:?} -> {:?}", backup_path_dl, backup_path);
+
+    // Make sure that the rename is on disk as well
+    if fsync {
+        let dir = backup_path.parent().expect("Backup path without parent");
+        let started = Instant::now();
+        sync_dir(dir)
+            .await
+            .with_context(|| format!("Could not sync directory {:?}", dir))?;
+        metrics().fsync_dir.observe(started.elapsed());
+    }
+
+    Ok((updated, hex(&hasher.finalize())))
+}
+
+impl BackupStore for FilesystemStore {
+    fn get<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<Option<Backup>>> {
+        Box::pin(async move {
+            Ok(self
+                .open(backup_id)
+                .await?
+                .map(|(reader, metadata)| Backup {
+                    metadata,
+                    body: Body::wrap_stream(reader.into_stream()),
+                }))
+        })
+    }
+
+    fn get_range<'a>(
+        &'a self,
+        backup_id: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, anyhow::Result<Option<Backup>>> {
+        Box::pin(async move {
+            let (mut reader, metadata) = match self.open(backup_id).await? {
+                Some(backup) => backup,
+                None => return Ok(None),
+            };
+            reader.seek(range.start).await?;
+            let body = Body::wrap_stream(reader.into_stream());
+            Ok(Some(Backup {
+                metadata,
+                body: slice_body(body, 0..range.end - range.start),
+            }))
+        })
+    }
+
+    fn head<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            Ok(matches!(self.find(backup_id).await?, Some((_, metadata)) if metadata.is_file()))
+        })
+    }
+
+    fn put<'a>(&'a self, backup_id: &'a str, body: Body) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(self.store(backup_id, body))
+    }
+
+    fn delete<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let backup_path = match self.find(backup_id).await? {
+                Some((path, metadata)) if metadata.is_file() => path,
+                Some((path, _)) => bail!(
+                    "Tried to delete a backup path that exists but is not a file: {:?}",
+                    path
+                ),
+                None => {
+                    trace!(
+                        "Tried to delete a backup that does not exist: {}",
+                        backup_id
+                    );
+                    return Ok(false);
+                }
+            };
+            fs::remove_file(&backup_path)
+                .await
+                .with_context(|| format!("Could not delete backup at {:?}", backup_path))?;
+            remove_dir_if_exists(&self.versions_dir(backup_id)).await?;
+            self.etags
+                .lock()
+                .expect("ETag cache lock is poisoned")
+                .remove(backup_id);
+            Ok(true)
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, BackupMetadata)>>> {
+        Box::pin(async move {
+            let mut backups = vec![];
+            scan_dir(&self.backup_dir, "", &mut backups).await?;
+            if self.sharded {
+                for first in shard_dirs(&self.backup_dir).await? {
+                    let first_dir = self.backup_dir.join(&first);
+                    for second in shard_dirs(&first_dir).await? {
+                        let prefix = format!("{}{}", first, second);
+                        scan_dir(&first_dir.join(&second), &prefix, &mut backups).await?;
+                    }
+                }
+            }
+            Ok(backups)
+        })
+    }
+
+    fn stat<'a>(
+        &'a self,
+        backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Option<BackupMetadata>>> {
+        Box::pin(async move {
+            let (path, metadata) = match self.find(backup_id).await? {
+                Some((path, metadata)) if metadata.is_file() => (path, metadata),
+                _ => return Ok(None),
+            };
+            let mut backup_metadata = backup_metadata(&metadata)?;
+            backup_metadata.etag = self.cached_etag(backup_id, &backup_metadata);
+            backup_metadata.size = self.backup_size(&path, &metadata).await?;
+            Ok(Some(backup_metadata))
+        })
+    }
+
+    fn etag<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<Option<String>>> {
+        Box::pin(async move {
+            let (mut reader, metadata) = match self.open_file(backup_id).await? {
+                Some(backup) => backup,
+                None => return Ok(None),
+            };
+            Ok(Some(
+                self.compute_etag(backup_id, &mut reader, &metadata).await?,
+            ))
+        })
+    }
+
+    fn versions<'a>(
+        &'a self,
+        backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<BackupVersion>>> {
+        Box::pin(async move {
+            let mut versions = vec![];
+            for (version, path, metadata) in self.read_versions(backup_id).await? {
+                versions.push(BackupVersion {
+                    version,
+                    metadata: BackupMetadata {
+                        size: self.backup_size(&path, &metadata).await?,
+                        ..backup_metadata(&metadata)?
+                    },
+                });
+            }
+            Ok(versions)
+        })
+    }
+
+    fn restore_version<'a>(
+        &'a self,
+        backup_id: &'a str,
+        version: u32,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let version_path = self.versions_dir(backup_id).join(version.to_string());
+            let file = match fs::File::open(&version_path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Could not open version {:?}", version_path))
+                }
+            };
+            let file_size = file
+                .metadata()
+                .await
+                .with_context(|| format!("Could not read metadata of {:?}", version_path))?
+                .len();
+            let reader = self.reader(file, file_size).await?;
+            // Write a copy, so that the version stays unchanged
+            self.store(backup_id, Body::wrap_stream(reader.into_stream()))
+                .await?;
+            Ok(true)
+        })
+    }
+
+    fn trash<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let backup_path = match self.find(backup_id).await? {
+                Some((path, metadata)) if metadata.is_file() => path,
+                Some((path, _)) => bail!(
+                    "Tried to delete a backup path that exists but is not a file: {:?}",
+                    path
+                ),
+                None => return Ok(false),
+            };
+            let trash_dir = self.trash_dir(backup_id);
+            remove_dir_if_exists(&trash_dir).await?;
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(&trash_dir)
+                .await
+                .with_context(|| format!("Could not create directory {:?}", trash_dir))?;
+
+            // Record the deletion time first, so that an incomplete move can
+            // be purged as well
+            let deleted = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let deleted_path = trash_dir.join("deleted");
+            let mut deleted_file = create_file(&deleted_path)
+                .await
+                .with_context(|| format!("Could not create {:?}", deleted_path))?;
+            deleted_file
+                .write_all(deleted.to_string().as_bytes())
+                .await
+                .with_context(|| format!("Could not write {:?}", deleted_path))?;
+            if self.fsync {
+                deleted_file
+                    .sync_all()
+                    .await
+                    .with_context(|| format!("Could not sync {:?}", deleted_path))?;
+            }
+            drop(deleted_file);
+
+            fs::rename(&backup_path, trash_dir.join("backup"))
+                .await
+                .with_context(|| format!("Could not move backup {:?} to the trash", backup_path))?;
+            let versions_dir = self.versions_dir(backup_id);
+            if metadata_if_exists(&versions_dir).await?.is_some() {
+                fs::rename(&versions_dir, trash_dir.join("versions"))
+                    .await
+                    .with_context(|| {
+                        format!("Could not move versions {:?} to the trash", versions_dir)
+                    })?;
+            }
+            if self.fsync {
+                sync_dir(&trash_dir)
+                    .await
+                    .context("Could not sync trash directory")?;
+                let dir = backup_path.parent().expect("Backup path without parent");
+                sync_dir(dir)
+                    .await
+                    .with_context(|| format!("Could not sync directory {:?}", dir))?;
+            }
+            self.etags
+                .lock()
+                .expect("ETag cache lock is poisoned")
+                .remove(backup_id);
+            Ok(true)
+        })
+    }
+
+    fn list_trash(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, TrashedBackup)>>> {
+        Box::pin(async move {
+            Ok(self
+                .read_trash()
+                .await?
+                .into_iter()
+                .filter_map(|(backup_id, _, deleted, metadata)| {
+                    Some((
+                        backup_id,
+                        TrashedBackup {
+                            deleted,
+                            metadata: metadata?,
+                        },
+                    ))
+                })
+                .collect())
+        })
+    }
+
+    fn untrash<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let trash_dir = self.trash_dir(backup_id);
+            let trashed_path = trash_dir.join("backup");
+            if metadata_if_exists(&trashed_path).await?.is_none() {
+                return Ok(false);
+            }
+            let backup_path = self.backup_path(backup_id);
+            if self.sharded {
+                create_parent_dirs(&backup_path).await?;
+            }
+            let trashed_versions = trash_dir.join("versions");
+            if metadata_if_exists(&trashed_versions).await?.is_some() {
+                let versions_dir = self.versions_dir(backup_id);
+                remove_dir_if_exists(&versions_dir).await?;
+                create_parent_dirs(&versions_dir).await?;
+                fs::rename(&trashed_versions, &versions_dir)
+                    .await
+                    .with_context(|| format!("Could not restore versions {:?}", versions_dir))?;
+            }
+            fs::rename(&trashed_path, &backup_path)
+                .await
+                .with_context(|| format!("Could not restore backup {:?}", backup_path))?;
+            if self.fsync {
+                let dir = backup_path.parent().expect("Backup path without parent");
+                sync_dir(dir)
+                    .await
+                    .with_context(|| format!("Could not sync directory {:?}", dir))?;
+            }
+            remove_dir_if_exists(&trash_dir).await?;
+            Ok(true)
+        })
+    }
+
+    fn purge_trash(&self, deleted_before: SystemTime) -> BoxFuture<'_, anyhow::Result<usize>> {
+        Box::pin(async move {
+            let mut purged = 0;
+            for (backup_id, path, deleted, _) in self.read_trash().await? {
+                if deleted >= deleted_before {
+                    continue;
+                }
+                remove_dir_if_exists(&path).await?;
+                debug!("Purged backup {} from the trash", backup_id);
+                purged += 1;
+            }
+            Ok(purged)
+        })
+    }
+
+    fn cleanup(&self) -> BoxFuture<'_, anyhow::Result<usize>> {
+        Box::pin(async move {
+            let paths: Vec<PathBuf> = self
+                .temp_files
+                .lock()
+                .expect("Temp files lock is poisoned")
+                .drain()
+                .collect();
+            let mut removed = 0;
+            for path in paths {
+                match fs::remove_file(&path).await {
+                    Ok(()) => {
+                        debug!("Removed temporary upload {:?}", path);
+                        removed += 1;
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("Could not remove temporary upload {:?}", path)
+                        })
+                    }
+                }
+            }
+            Ok(removed)
+        })
+    }
+
+    fn remove_orphans(&self, modified_before: SystemTime) -> BoxFuture<'_, anyhow::Result<usize>> {
+        Box::pin(async move {
+            let mut candidates = vec![];
+            scan_temp_files(&self.backup_dir, &mut candidates).await?;
+            if self.sharded {
+                for first in shard_dirs(&self.backup_dir).await? {
+                    let first_dir = self.backup_dir.join(&first);
+                    for second in shard_dirs(&first_dir).await? {
+                        scan_temp_files(&first_dir.join(&second), &mut candidates).await?;
+                    }
+                }
+            }
+
+            let mut removed = 0;
+            for (path, metadata) in candidates {
+                // Uploads in progress are not orphaned, no matter how slow they are
+                let in_progress = self
+                    .temp_files
+                    .lock()
+                    .expect("Temp files lock is poisoned")
+                    .contains(&path);
+                let modified = metadata
+                    .modified()
+                    .context("Could not read temporary file modification time")?;
+                if in_progress || modified >= modified_before {
+                    continue;
+                }
+                match fs::remove_file(&path).await {
+                    Ok(()) => {
+                        debug!("Removed orphaned temporary upload {:?}", path);
+                        removed += 1;
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("Could not remove orphaned temporary upload {:?}", path)
+                        })
+                    }
+                }
+            }
+            Ok(removed)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_backup_path() {
+        let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let flat = FilesystemStore::new(Path::new("/backups"), &FilesystemConfig::default());
+        assert_eq!(
+            flat.backup_path(backup_id),
+            Path::new("/backups").join(backup_id)
+        );
+        let sharded = FilesystemStore::new(
+            Path::new("/backups"),
+            &FilesystemConfig {
+                sharded: Some(true),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            sharded.backup_path(backup_id),
+            Path::new("/backups/01/23").join(backup_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aborted_upload_cleanup() {
+        let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(dir.path(), &FilesystemConfig::default());
+        let dir_entries = || std::fs::read_dir(dir.path()).unwrap().count();
+
+        // Start an upload that never finishes
+        let (mut sender, body) = Body::channel();
+        sender.send_data("sekur".into()).await.unwrap();
+        let mut upload = store.put(backup_id, body);
+        let pending = tokio::time::timeout(Duration::from_millis(100), &mut upload).await;
+        assert!(pending.is_err());
+        assert_eq!(dir_entries(), 1);
+
+        assert_eq!(store.cleanup().await.unwrap(), 1);
+        assert_eq!(dir_entries(), 0);
+
+        // Aborting the request removes the temporary file as well
+        let (mut sender, body) = Body::channel();
+        sender.send_data("sekur".into()).await.unwrap();
+        let mut upload = store.put(backup_id, body);
+        let pending = tokio::time::timeout(Duration::from_millis(100), &mut upload).await;
+        assert!(pending.is_err());
+        assert_eq!(dir_entries(), 1);
+        drop(upload);
+        assert_eq!(dir_entries(), 0);
+        assert_eq!(store.cleanup().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lazy_etag() {
+        let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(dir.path(), &FilesystemConfig::default());
+
+        // Backups stored by another process are only hashed on demand
+        std::fs::write(dir.path().join(backup_id), b"sekur").unwrap();
+        let metadata = store.stat(backup_id).await.unwrap().unwrap();
+        assert_eq!(metadata.size, 5);
+        assert_eq!(metadata.etag, None);
+        let etag = hex(&Sha256::digest(b"sekur"));
+        assert_eq!(store.etag(backup_id).await.unwrap(), Some(etag.clone()));
+        assert_eq!(
+            store.stat(backup_id).await.unwrap().unwrap().etag,
+            Some(etag)
+        );
+
+        // Uploaded backups are hashed while they are written
+        store.put(backup_id, "sekura".into()).await.unwrap();
+        let etag = hex(&Sha256::digest(b"sekura"));
+        assert_eq!(
+            store.stat(backup_id).await.unwrap().unwrap().etag,
+            Some(etag)
+        );
+        assert_eq!(store.etag(&"f".repeat(64)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_fsync() {
+        let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(
+            dir.path(),
+            &FilesystemConfig {
+                sharded: Some(true),
+                fsync: Some(true),
+            },
+        );
+        let fsync_file_count = metrics().fsync_file.count();
+        let fsync_dir_count = metrics().fsync_dir.count();
+        assert!(!store.put(backup_id, "sekur".into()).await.unwrap());
+        let backup = store.get(backup_id).await.unwrap().unwrap();
+        assert_eq!(backup.metadata.size, 5);
+        let body = hyper::body::to_bytes(backup.body).await.unwrap();
+        assert_eq!(&body[..], b"sekur");
+        assert!(metrics().fsync_file.count() > fsync_file_count);
+        assert!(metrics().fsync_dir.count() > fsync_dir_count);
+    }
+
+    #[tokio::test]
+    async fn test_encryption() {
+        let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("keys");
+        let old_key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let new_key = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+        let backup_dir = dir.path().join("backups");
+        std::fs::create_dir(&backup_dir).unwrap();
+        let store = |keys: &str| {
+            std::fs::write(&key_file, keys).unwrap();
+            FilesystemStore::new(&backup_dir, &FilesystemConfig::default())
+                .with_versions(1)
+                .with_encryption(Arc::new(Keys::from_file(&key_file).unwrap()))
+        };
+        let read = |store: FilesystemStore| async move {
+            let backup = store.get(backup_id).await.unwrap().unwrap();
+            let body = hyper::body::to_bytes(backup.body).await.unwrap();
+            (backup.metadata, body)
+        };
+
+        // Backups that were stored before encryption was enabled stay readable
+        let plain = FilesystemStore::new(&backup_dir, &FilesystemConfig::default());
+        plain.put(backup_id, "sekur".into()).await.unwrap();
+        let old_store = store(old_key);
+        assert_eq!(read(old_store.clone()).await.1, "sekur");
+
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        old_store.put(backup_id, data.clone().into()).await.unwrap();
+        let on_disk = std::fs::read(backup_dir.join(backup_id)).unwrap();
+        assert!(encryption::is_encrypted(&on_disk));
+        assert!(on_disk.len() > data.len());
+        let (metadata, body) = read(old_store.clone()).await;
+        assert_eq!(metadata.size, data.len() as u64);
+        assert_eq!(metadata.etag.unwrap(), hex(&Sha256::digest(&data)));
+        assert_eq!(body, data);
+        let range = CHUNK_SIZE as u64 - 5..CHUNK_SIZE as u64 + 5;
+        let backup = old_store
+            .get_range(backup_id, range.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let body = hyper::body::to_bytes(backup.body).await.unwrap();
+        assert_eq!(body, data[range.start as usize..range.end as usize]);
+        let versions = old_store.versions(backup_id).await.unwrap();
+        assert_eq!(versions[0].metadata.size, 5);
+
+        // Rotate the key
+        let new_store = store(&format!("{}\n{}\n", new_key, old_key));
+        let modified = std::fs::metadata(backup_dir.join(backup_id))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(new_store.reencrypt().await.unwrap(), 2);
+        assert_eq!(new_store.reencrypt().await.unwrap(), 0);
+        let metadata = std::fs::metadata(backup_dir.join(backup_id)).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        let only_new_store = store(new_key);
+        assert_eq!(read(only_new_store.clone()).await.1, data);
+        assert!(only_new_store.restore_version(backup_id, 1).await.unwrap());
+        assert_eq!(read(only_new_store.clone()).await.1, "sekur");
+
+        // Backups encrypted with an unknown key cannot be read
+        assert!(store(old_key).get(backup_id).await.is_err());
+
+        // Encrypted backups are not served without keys
+        assert!(plain.get(backup_id).await.is_err());
+        assert!(plain.stat(backup_id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_detected_by_header() {
+        let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("keys");
+        std::fs::write(
+            &key_file,
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        )
+        .unwrap();
+        let backup_dir = dir.path().join("backups");
+        let copy_dir = dir.path().join("copy");
+        std::fs::create_dir(&backup_dir).unwrap();
+        std::fs::create_dir(&copy_dir).unwrap();
+        let keys = Arc::new(Keys::from_file(&key_file).unwrap());
+
+        // Encrypted backups stay encrypted when they are copied
+        let store = FilesystemStore::new(&backup_dir, &FilesystemConfig::default())
+            .with_encryption(keys.clone());
+        store.put(backup_id, "sekur".into()).await.unwrap();
+        std::fs::copy(backup_dir.join(backup_id), copy_dir.join(backup_id)).unwrap();
+        let plain_copy = FilesystemStore::new(&copy_dir, &FilesystemConfig::default());
+        assert!(plain_copy.get(backup_id).await.is_err());
+        let copy = plain_copy.clone().with_encryption(keys);
+        let backup = copy.get(backup_id).await.unwrap().unwrap();
+        assert_eq!(hyper::body::to_bytes(backup.body).await.unwrap(), "sekur");
+
+        // A plaintext backup that was crafted to look like an encrypted one
+        // is never served, with or without keys
+        let mut data = b"\0SKRENC\n\x01".to_vec();
+        data.resize(HEADER_LEN + TRAILER_LEN + 100, 42);
+        plain_copy.put(backup_id, data.into()).await.unwrap();
+        assert!(plain_copy.get(backup_id).await.is_err());
+        assert!(copy.get(backup_id).await.is_err());
+    }
+}
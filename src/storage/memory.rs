@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use hyper::Body;
+use sha2::{Digest, Sha256};
+
+use super::{hex, Backup, BackupMetadata, BackupStore, BackupVersion, TrashedBackup};
+
+/// A backup in memory, along with its previous versions.
+#[derive(Debug)]
+struct StoredBackup {
+    bytes: Vec<u8>,
+    metadata: BackupMetadata,
+    /// Previous versions, oldest first
+    versions: Vec<(BackupVersion, Vec<u8>)>,
+}
+
+/// A backup store that keeps all backups in memory.
+///
+/// All backups are lost when the server is stopped, so this is only useful
+/// for tests and throwaway instances.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    backups: Mutex<HashMap<String, StoredBackup>>,
+    /// Deleted backups along with the time of deletion
+    trash: Mutex<HashMap<String, (SystemTime, StoredBackup)>>,
+    keep_versions: u32,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the given number of previous versions of every backup.
+    pub fn with_versions(mut self, keep_versions: u32) -> Self {
+        self.keep_versions = keep_versions;
+        self
+    }
+
+    fn backups(&self) -> MutexGuard<'_, HashMap<String, StoredBackup>> {
+        self.backups.lock().expect("Memory store lock is poisoned")
+    }
+
+    /// Lock the trash.
+    ///
+    /// If the backups are needed as well, they must be locked first.
+    fn trashed(&self) -> MutexGuard<'_, HashMap<String, (SystemTime, StoredBackup)>> {
+        self.trash.lock().expect("Memory store lock is poisoned")
+    }
+
+    /// Store a backup, keeping the previous backup as a version.
+    ///
+    /// Return true if an existing backup was updated.
+    fn store(&self, backup_id: &str, bytes: Vec<u8>) -> bool {
+        let metadata = BackupMetadata {
+            size: bytes.len() as u64,
+            modified: SystemTime::now(),
+            etag: Some(hex(&Sha256::digest(&bytes))),
+        };
+        let mut backups = self.backups();
+        let existing = match backups.get_mut(backup_id) {
+            Some(existing) => existing,
+            None => {
+                backups.insert(
+                    backup_id.to_string(),
+                    StoredBackup {
+                        bytes,
+                        metadata,
+                        versions: vec![],
+                    },
+                );
+                return false;
+            }
+        };
+        let previous_bytes = std::mem::replace(&mut existing.bytes, bytes);
+        let previous_metadata = std::mem::replace(&mut existing.metadata, metadata);
+        if self.keep_versions > 0 {
+            let version = existing
+                .versions
+                .last()
+                .map_or(1, |(version, _)| version.version + 1);
+            existing.versions.push((
+                BackupVersion {
+                    version,
+                    metadata: previous_metadata,
+                },
+                previous_bytes,
+            ));
+        }
+        let excess = existing
+            .versions
+            .len()
+            .saturating_sub(self.keep_versions as usize);
+        existing.versions.drain(..excess);
+        true
+    }
+}
+
+impl BackupStore for MemoryStore {
+    fn get<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<Option<Backup>>> {
+        Box::pin(async move {
+            Ok(self.backups().get(backup_id).map(|backup| Backup {
+                metadata: backup.metadata.clone(),
+                body: backup.bytes.clone().into(),
+            }))
+        })
+    }
+
+    fn get_range<'a>(
+        &'a self,
+        backup_id: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, anyhow::Result<Option<Backup>>> {
+        Box::pin(async move {
+            Ok(self.backups().get(backup_id).map(|backup| {
+                let end = (range.end as usize).min(backup.bytes.len());
+                let start = (range.start as usize).min(end);
+                Backup {
+                    metadata: backup.metadata.clone(),
+                    body: backup.bytes[start..end].to_vec().into(),
+                }
+            }))
+        })
+    }
+
+    fn head<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move { Ok(self.backups().contains_key(backup_id)) })
+    }
+
+    fn put<'a>(&'a self, backup_id: &'a str, body: Body) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            // Read the whole body before storing anything, so that incomplete
+            // uploads are never persisted
+            let bytes = hyper::body::to_bytes(body)
+                .await
+                .context("Could not read body")?;
+            Ok(self.store(backup_id, bytes.to_vec()))
+        })
+    }
+
+    fn delete<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move { Ok(self.backups().remove(backup_id).is_some()) })
+    }
+
+    fn list(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, BackupMetadata)>>> {
+        Box::pin(async move {
+            Ok(self
+                .backups()
+                .iter()
+                .map(|(backup_id, backup)| (backup_id.clone(), backup.metadata.clone()))
+                .collect())
+        })
+    }
+
+    fn stat<'a>(
+        &'a self,
+        backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Option<BackupMetadata>>> {
+        Box::pin(async move {
+            Ok(self
+                .backups()
+                .get(backup_id)
+                .map(|backup| backup.metadata.clone()))
+        })
+    }
+
+    fn versions<'a>(
+        &'a self,
+        backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<BackupVersion>>> {
+        Box::pin(async move {
+            Ok(self
+                .backups()
+                .get(backup_id)
+                .map(|backup| {
+                    backup
+                        .versions
+                        .iter()
+                        .map(|(version, _)| version.clone())
+                        .collect()
+                })
+                .unwrap_or_default())
+        })
+    }
+
+    fn restore_version<'a>(
+        &'a self,
+        backup_id: &'a str,
+        version: u32,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let bytes = self.backups().get(backup_id).and_then(|backup| {
+                backup
+                    .versions
+                    .iter()
+                    .find(|(v, _)| v.version == version)
+                    .map(|(_, bytes)| bytes.clone())
+            });
+            match bytes {
+                Some(bytes) => {
+                    self.store(backup_id, bytes);
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn trash<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let mut backups = self.backups();
+            match backups.remove(backup_id) {
+                Some(backup) => {
+                    self.trashed()
+                        .insert(backup_id.to_string(), (SystemTime::now(), backup));
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn list_trash(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, TrashedBackup)>>> {
+        Box::pin(async move {
+            Ok(self
+                .trashed()
+                .iter()
+                .map(|(backup_id, (deleted, backup))| {
+                    (
+                        backup_id.clone(),
+                        TrashedBackup {
+                            deleted: *deleted,
+                            metadata: backup.metadata.clone(),
+                        },
+                    )
+                })
+                .collect())
+        })
+    }
+
+    fn untrash<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let mut backups = self.backups();
+            match self.trashed().remove(backup_id) {
+                Some((_, backup)) => {
+                    backups.insert(backup_id.to_string(), backup);
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn purge_trash(&self, deleted_before: SystemTime) -> BoxFuture<'_, anyhow::Result<usize>> {
+        Box::pin(async move {
+            let mut trash = self.trashed();
+            let count = trash.len();
+            trash.retain(|_, (deleted, _)| *deleted >= deleted_before);
+            Ok(count - trash.len())
+        })
+    }
+}
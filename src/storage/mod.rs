@@ -0,0 +1,308 @@
+//! Storage backends for backups.
+
+use std::{ops::Range, sync::Arc, time::SystemTime};
+
+use anyhow::bail;
+use futures::{future::BoxFuture, stream};
+use hyper::{body::HttpBody, Body};
+
+use crate::config::{ServerConfig, StorageConfig};
+
+mod encryption;
+mod filesystem;
+mod memory;
+mod s3;
+mod sqlite;
+
+pub use self::{
+    encryption::Keys, filesystem::FilesystemStore, memory::MemoryStore, s3::S3Store,
+    sqlite::SqliteStore,
+};
+
+/// Metadata of a stored backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupMetadata {
+    /// The size of the backup in bytes
+    pub size: u64,
+    /// The time of the last upload
+    pub modified: SystemTime,
+    /// A hash of the contents, without quotes
+    ///
+    /// This is only known when a single backup is requested, listings may
+    /// omit it. Use [`BackupStore::etag`] if it is needed.
+    pub etag: Option<String>,
+}
+
+/// A stored backup.
+#[derive(Debug)]
+pub struct Backup {
+    pub metadata: BackupMetadata,
+    /// The contents of the backup, streamed from the storage backend
+    pub body: Body,
+}
+
+/// A previous version of a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupVersion {
+    /// The number of the version, increasing with every upload
+    pub version: u32,
+    pub metadata: BackupMetadata,
+}
+
+/// A deleted backup in the trash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashedBackup {
+    /// The time of the deletion
+    pub deleted: SystemTime,
+    pub metadata: BackupMetadata,
+}
+
+/// A storage backend for backups.
+///
+/// All methods expect a valid backup id, validation is done by the caller.
+pub trait BackupStore: Send + Sync {
+    /// Return a backup, or `None` if it does not exist.
+    ///
+    /// The body is `metadata.size` bytes long.
+    fn get<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<Option<Backup>>>;
+
+    /// Return a part of a backup, or `None` if it does not exist.
+    ///
+    /// The body only contains the bytes in `range`, while the metadata
+    /// describes the whole backup. The range is expected to lie within the
+    /// backup. By default, the bytes before the range are skipped while
+    /// reading the whole backup.
+    fn get_range<'a>(
+        &'a self,
+        backup_id: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, anyhow::Result<Option<Backup>>> {
+        Box::pin(async move {
+            Ok(self.get(backup_id).await?.map(|backup| Backup {
+                metadata: backup.metadata,
+                body: slice_body(backup.body, range),
+            }))
+        })
+    }
+
+    /// Return whether a backup exists.
+    fn head<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Store a backup, replacing an existing backup with the same id.
+    ///
+    /// Return true if an existing backup was updated, or false if a new backup was created.
+    fn put<'a>(&'a self, backup_id: &'a str, body: Body) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Delete a backup.
+    ///
+    /// Return false if the backup did not exist.
+    fn delete<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Return the ids and metadata of all stored backups.
+    fn list(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, BackupMetadata)>>>;
+
+    /// Return the metadata of a backup, or `None` if it does not exist.
+    ///
+    /// The ETag may be omitted if it is expensive to determine.
+    fn stat<'a>(
+        &'a self,
+        backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Option<BackupMetadata>>>;
+
+    /// Return the content hash of a backup, or `None` if it does not exist.
+    ///
+    /// By default, the ETag returned by `stat` is used.
+    fn etag<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<Option<String>>> {
+        Box::pin(async move {
+            Ok(self
+                .stat(backup_id)
+                .await?
+                .and_then(|metadata| metadata.etag))
+        })
+    }
+
+    /// Return the previous versions of a backup that are kept, oldest first.
+    ///
+    /// Backends that do not keep previous versions return an empty list.
+    fn versions<'a>(
+        &'a self,
+        _backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<BackupVersion>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    /// Replace a backup with a copy of one of its previous versions.
+    ///
+    /// The replaced backup is kept as a previous version itself. Return false
+    /// if the version does not exist.
+    fn restore_version<'a>(
+        &'a self,
+        _backup_id: &'a str,
+        _version: u32,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    /// Move a backup and its previous versions to the trash.
+    ///
+    /// A backup with the same id that is already in the trash is replaced.
+    /// Return false if the backup did not exist.
+    fn trash<'a>(&'a self, _backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async { bail!("The storage backend does not support a trash") })
+    }
+
+    /// Return the ids of all backups in the trash, along with the time of
+    /// their deletion.
+    fn list_trash(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, TrashedBackup)>>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    /// Move a backup from the trash back into place.
+    ///
+    /// The caller must make sure that no backup with the same id exists.
+    /// Return false if the backup is not in the trash.
+    fn untrash<'a>(&'a self, _backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    /// Permanently delete the backups in the trash that were deleted before
+    /// `deleted_before`.
+    ///
+    /// Return the number of purged backups.
+    fn purge_trash(&self, _deleted_before: SystemTime) -> BoxFuture<'_, anyhow::Result<usize>> {
+        Box::pin(async { Ok(0) })
+    }
+
+    /// Remove the partial data of uploads that are still in progress.
+    ///
+    /// This is called on shutdown, once in-flight requests had time to finish.
+    /// Return the number of aborted uploads.
+    fn cleanup(&self) -> BoxFuture<'_, anyhow::Result<usize>> {
+        Box::pin(async { Ok(0) })
+    }
+
+    /// Remove the partial data of uploads that were interrupted by a crash
+    /// and last modified before `modified_before`.
+    ///
+    /// Return the number of removed uploads.
+    fn remove_orphans(&self, _modified_before: SystemTime) -> BoxFuture<'_, anyhow::Result<usize>> {
+        Box::pin(async { Ok(0) })
+    }
+}
+
+/// Encode bytes as lowercase hex string.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Return a body with only the bytes of `body` that lie in `range`.
+///
+/// Reading stops at the end of the range.
+pub(crate) fn slice_body(body: Body, range: Range<u64>) -> Body {
+    let stream = stream::try_unfold((body, 0), move |(mut body, mut offset): (Body, u64)| {
+        let range = range.clone();
+        async move {
+            while offset < range.end {
+                let chunk = match body.data().await {
+                    Some(chunk) => chunk?,
+                    None => break,
+                };
+                let chunk_start = offset;
+                offset += chunk.len() as u64;
+                let start = range
+                    .start
+                    .saturating_sub(chunk_start)
+                    .min(chunk.len() as u64);
+                let end = (range.end - chunk_start).min(chunk.len() as u64);
+                if start < end {
+                    let part = chunk.slice(start as usize..end as usize);
+                    return Ok::<_, hyper::Error>(Some((part, (body, offset))));
+                }
+            }
+            Ok(None)
+        }
+    });
+    Body::wrap_stream(stream)
+}
+
+/// Check the storage settings of the server config, without opening the
+/// storage backend.
+pub fn check_config(config: &ServerConfig) -> anyhow::Result<()> {
+    let keep_versions = config.keep_versions.unwrap_or(0);
+    match config.storage.clone().unwrap_or_default() {
+        StorageConfig::Filesystem(_) => {
+            if let Some(path) = &config.encryption_key_file {
+                Keys::from_file(path)?;
+            }
+        }
+        other if config.encryption_key_file.is_some() => {
+            bail!("The {} backend does not support encryption_key_file", other)
+        }
+        StorageConfig::Memory => {}
+        StorageConfig::S3(_) if keep_versions > 0 => bail!(
+            "The S3 backend does not support keep_versions, enable versioning on the bucket instead"
+        ),
+        StorageConfig::S3(_) if config.trash_days.unwrap_or(0) > 0 => bail!(
+            "The S3 backend does not support trash_days, enable versioning on the bucket instead"
+        ),
+        StorageConfig::S3(s3_config) => {
+            S3Store::check_config(&s3_config)?;
+        }
+        StorageConfig::Sqlite(sqlite_config) => {
+            let path = sqlite_config.path(&config.backup_dir);
+            if path.is_dir() {
+                bail!("SQLite database {:?} is a directory", path);
+            }
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    bail!("Directory of the SQLite database {:?} does not exist", path)
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create the storage backend selected in the server config.
+pub fn from_config(config: &ServerConfig) -> anyhow::Result<Arc<dyn BackupStore>> {
+    check_config(config)?;
+    let keep_versions = config.keep_versions.unwrap_or(0);
+    Ok(match config.storage.clone().unwrap_or_default() {
+        StorageConfig::Filesystem(fs_config) => {
+            let mut store =
+                FilesystemStore::new(&config.backup_dir, &fs_config).with_versions(keep_versions);
+            if let Some(path) = &config.encryption_key_file {
+                store = store.with_encryption(Arc::new(Keys::from_file(path)?));
+            }
+            Arc::new(store)
+        }
+        StorageConfig::Memory => Arc::new(MemoryStore::new().with_versions(keep_versions)),
+        StorageConfig::S3(s3_config) => Arc::new(S3Store::new(&s3_config)?),
+        StorageConfig::Sqlite(sqlite_config) => Arc::new(
+            SqliteStore::open(&sqlite_config.path(&config.backup_dir))?
+                .with_versions(keep_versions),
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slice_body() {
+        let slice = |range| async move {
+            let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("abc"), Ok("def"), Ok("ghi")];
+            let body = Body::wrap_stream(stream::iter(chunks));
+            hyper::body::to_bytes(slice_body(body, range))
+                .await
+                .unwrap()
+        };
+        assert_eq!(slice(0..9).await, "abcdefghi");
+        assert_eq!(slice(1..2).await, "b");
+        assert_eq!(slice(2..7).await, "cdefg");
+        assert_eq!(slice(3..6).await, "def");
+        assert_eq!(slice(8..20).await, "i");
+    }
+}
@@ -0,0 +1,557 @@
+use std::{
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use hyper::Body;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+
+use super::{hex, Backup, BackupMetadata, BackupStore, BackupVersion, TrashedBackup};
+
+/// The maximum size of a blob in SQLite, larger offsets and lengths overflow
+/// in `substr`.
+const MAX_BLOB_SIZE: u64 = i32::MAX as u64;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS backups (
+        backup_id TEXT PRIMARY KEY,
+        data BLOB NOT NULL,
+        size INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        last_access INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS versions (
+        backup_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        size INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (backup_id, version)
+    );
+    CREATE TABLE IF NOT EXISTS trash (
+        backup_id TEXT PRIMARY KEY,
+        deleted_at INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        size INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        last_access INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS trash_versions (
+        backup_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        size INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (backup_id, version)
+    );
+";
+
+/// A backup store that keeps all backups in a single SQLite database.
+///
+/// Along with the contents, the time of the first upload (`created_at`), of
+/// the last upload (`updated_at`) and of the last download (`last_access`)
+/// are stored, in milliseconds since the Unix epoch.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+    keep_versions: u32,
+}
+
+impl SqliteStore {
+    /// Open the database at the given path, creating it if necessary.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Could not open SQLite database at {:?}", path))?;
+        Self::with_connection(connection)
+    }
+
+    /// Open a database that only lives in memory, for tests.
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> anyhow::Result<Self> {
+        connection
+            .execute_batch(SCHEMA)
+            .context("Could not create SQLite schema")?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            keep_versions: 0,
+        })
+    }
+
+    /// Keep the given number of previous versions of every backup.
+    pub fn with_versions(mut self, keep_versions: u32) -> Self {
+        self.keep_versions = keep_versions;
+        self
+    }
+
+    /// Run a function with the connection on the blocking thread pool.
+    ///
+    /// The function is run in a transaction, which is committed if it
+    /// succeeds.
+    async fn run<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&rusqlite::Transaction) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().expect("SQLite store lock is poisoned");
+            let transaction = connection.transaction()?;
+            let result = f(&transaction)?;
+            transaction.commit()?;
+            Ok::<_, rusqlite::Error>(result)
+        })
+        .await
+        .context("SQLite task failed")?
+        .context("SQLite query failed")
+    }
+}
+
+/// Store a backup, keeping the previous backup as a version.
+///
+/// Return true if an existing backup was updated.
+fn store(
+    transaction: &rusqlite::Transaction,
+    backup_id: &str,
+    data: &[u8],
+    keep_versions: u32,
+) -> rusqlite::Result<bool> {
+    let now = to_millis(SystemTime::now());
+    let updated = transaction
+        .query_row(
+            "SELECT 1 FROM backups WHERE backup_id = ?1",
+            [backup_id],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if updated && keep_versions > 0 {
+        transaction.execute(
+            "INSERT INTO versions (backup_id, version, data, size, hash, updated_at)
+             SELECT backup_id,
+                    (SELECT IFNULL(MAX(version), 0) + 1 FROM versions WHERE backup_id = ?1),
+                    data, size, hash, updated_at
+             FROM backups WHERE backup_id = ?1",
+            [backup_id],
+        )?;
+    }
+    transaction.execute(
+        "DELETE FROM versions WHERE backup_id = ?1 AND version NOT IN (
+             SELECT version FROM versions WHERE backup_id = ?1
+             ORDER BY version DESC LIMIT ?2
+         )",
+        params![backup_id, keep_versions],
+    )?;
+    transaction.execute(
+        "INSERT INTO backups (backup_id, data, size, hash, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+         ON CONFLICT (backup_id) DO UPDATE SET
+             data = excluded.data,
+             size = excluded.size,
+             hash = excluded.hash,
+             updated_at = excluded.updated_at",
+        params![
+            backup_id,
+            data,
+            data.len() as u64,
+            hex(&Sha256::digest(data)),
+            now
+        ],
+    )?;
+    Ok(updated)
+}
+
+/// Read the metadata from a row with the size, hash and update time.
+fn metadata(row: &Row, first: usize) -> rusqlite::Result<BackupMetadata> {
+    Ok(BackupMetadata {
+        size: row.get(first)?,
+        etag: Some(row.get(first + 1)?),
+        modified: from_millis(row.get(first + 2)?),
+    })
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+impl BackupStore for SqliteStore {
+    fn get<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<Option<Backup>>> {
+        self.get_range(backup_id, 0..u64::MAX)
+    }
+
+    fn get_range<'a>(
+        &'a self,
+        backup_id: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, anyhow::Result<Option<Backup>>> {
+        let backup_id = backup_id.to_string();
+        Box::pin(async move {
+            let backup = self
+                .run(move |transaction| {
+                    let backup = transaction
+                        .query_row(
+                            "SELECT substr(data, ?2, ?3), size, hash, updated_at
+                             FROM backups WHERE backup_id = ?1",
+                            params![
+                                backup_id,
+                                range.start.saturating_add(1).min(MAX_BLOB_SIZE),
+                                (range.end - range.start).min(MAX_BLOB_SIZE)
+                            ],
+                            |row| Ok((row.get::<_, Vec<u8>>(0)?, metadata(row, 1)?)),
+                        )
+                        .optional()?;
+                    if backup.is_some() {
+                        transaction.execute(
+                            "UPDATE backups SET last_access = ?2 WHERE backup_id = ?1",
+                            params![backup_id, to_millis(SystemTime::now())],
+                        )?;
+                    }
+                    Ok(backup)
+                })
+                .await?;
+            Ok(backup.map(|(data, metadata)| Backup {
+                metadata,
+                body: data.into(),
+            }))
+        })
+    }
+
+    fn head<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move { Ok(self.stat(backup_id).await?.is_some()) })
+    }
+
+    fn put<'a>(&'a self, backup_id: &'a str, body: Body) -> BoxFuture<'a, anyhow::Result<bool>> {
+        let backup_id = backup_id.to_string();
+        let keep_versions = self.keep_versions;
+        Box::pin(async move {
+            // Read the whole body before storing anything, so that incomplete
+            // uploads are never persisted
+            let data = hyper::body::to_bytes(body)
+                .await
+                .context("Could not read body")?;
+            self.run(move |transaction| store(transaction, &backup_id, &data, keep_versions))
+                .await
+        })
+    }
+
+    fn delete<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        let backup_id = backup_id.to_string();
+        Box::pin(async move {
+            self.run(move |transaction| {
+                transaction.execute("DELETE FROM versions WHERE backup_id = ?1", [&backup_id])?;
+                Ok(
+                    transaction
+                        .execute("DELETE FROM backups WHERE backup_id = ?1", [&backup_id])?
+                        > 0,
+                )
+            })
+            .await
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, BackupMetadata)>>> {
+        Box::pin(async move {
+            self.run(|transaction| {
+                let mut statement =
+                    transaction.prepare("SELECT backup_id, size, hash, updated_at FROM backups")?;
+                let backups = statement
+                    .query_map([], |row| Ok((row.get(0)?, metadata(row, 1)?)))?
+                    .collect();
+                backups
+            })
+            .await
+        })
+    }
+
+    fn stat<'a>(
+        &'a self,
+        backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Option<BackupMetadata>>> {
+        let backup_id = backup_id.to_string();
+        Box::pin(async move {
+            self.run(move |transaction| {
+                transaction
+                    .query_row(
+                        "SELECT size, hash, updated_at FROM backups WHERE backup_id = ?1",
+                        [backup_id],
+                        |row| metadata(row, 0),
+                    )
+                    .optional()
+            })
+            .await
+        })
+    }
+
+    fn versions<'a>(
+        &'a self,
+        backup_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<BackupVersion>>> {
+        let backup_id = backup_id.to_string();
+        Box::pin(async move {
+            self.run(move |transaction| {
+                let mut statement = transaction.prepare(
+                    "SELECT version, size, hash, updated_at FROM versions
+                     WHERE backup_id = ?1 ORDER BY version",
+                )?;
+                let versions = statement
+                    .query_map([backup_id], |row| {
+                        Ok(BackupVersion {
+                            version: row.get(0)?,
+                            metadata: metadata(row, 1)?,
+                        })
+                    })?
+                    .collect();
+                versions
+            })
+            .await
+        })
+    }
+
+    fn restore_version<'a>(
+        &'a self,
+        backup_id: &'a str,
+        version: u32,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        let backup_id = backup_id.to_string();
+        let keep_versions = self.keep_versions;
+        Box::pin(async move {
+            self.run(move |transaction| {
+                let data: Option<Vec<u8>> = transaction
+                    .query_row(
+                        "SELECT data FROM versions WHERE backup_id = ?1 AND version = ?2",
+                        params![backup_id, version],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                match data {
+                    Some(data) => store(transaction, &backup_id, &data, keep_versions),
+                    None => Ok(false),
+                }
+            })
+            .await
+        })
+    }
+
+    fn trash<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        let backup_id = backup_id.to_string();
+        Box::pin(async move {
+            self.run(move |transaction| {
+                let exists = transaction
+                    .query_row(
+                        "SELECT 1 FROM backups WHERE backup_id = ?1",
+                        [&backup_id],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some();
+                if !exists {
+                    return Ok(false);
+                }
+                transaction.execute("DELETE FROM trash WHERE backup_id = ?1", [&backup_id])?;
+                transaction.execute(
+                    "DELETE FROM trash_versions WHERE backup_id = ?1",
+                    [&backup_id],
+                )?;
+                transaction.execute(
+                    "INSERT INTO trash
+                     SELECT backup_id, ?2, data, size, hash, created_at, updated_at, last_access
+                     FROM backups WHERE backup_id = ?1",
+                    params![backup_id, to_millis(SystemTime::now())],
+                )?;
+                transaction.execute(
+                    "INSERT INTO trash_versions SELECT * FROM versions WHERE backup_id = ?1",
+                    [&backup_id],
+                )?;
+                transaction.execute("DELETE FROM versions WHERE backup_id = ?1", [&backup_id])?;
+                transaction.execute("DELETE FROM backups WHERE backup_id = ?1", [&backup_id])?;
+                Ok(true)
+            })
+            .await
+        })
+    }
+
+    fn list_trash(&self) -> BoxFuture<'_, anyhow::Result<Vec<(String, TrashedBackup)>>> {
+        Box::pin(async move {
+            self.run(|transaction| {
+                let mut statement = transaction
+                    .prepare("SELECT backup_id, deleted_at, size, hash, updated_at FROM trash")?;
+                let trash = statement
+                    .query_map([], |row| {
+                        Ok((
+                            row.get(0)?,
+                            TrashedBackup {
+                                deleted: from_millis(row.get(1)?),
+                                metadata: metadata(row, 2)?,
+                            },
+                        ))
+                    })?
+                    .collect();
+                trash
+            })
+            .await
+        })
+    }
+
+    fn untrash<'a>(&'a self, backup_id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        let backup_id = backup_id.to_string();
+        Box::pin(async move {
+            self.run(move |transaction| {
+                let restored = transaction.execute(
+                    "INSERT INTO backups
+                     SELECT backup_id, data, size, hash, created_at, updated_at, last_access
+                     FROM trash WHERE backup_id = ?1",
+                    [&backup_id],
+                )?;
+                if restored == 0 {
+                    return Ok(false);
+                }
+                transaction.execute(
+                    "INSERT INTO versions SELECT * FROM trash_versions WHERE backup_id = ?1",
+                    [&backup_id],
+                )?;
+                transaction.execute(
+                    "DELETE FROM trash_versions WHERE backup_id = ?1",
+                    [&backup_id],
+                )?;
+                transaction.execute("DELETE FROM trash WHERE backup_id = ?1", [&backup_id])?;
+                Ok(true)
+            })
+            .await
+        })
+    }
+
+    fn purge_trash(&self, deleted_before: SystemTime) -> BoxFuture<'_, anyhow::Result<usize>> {
+        let deleted_before = to_millis(deleted_before);
+        Box::pin(async move {
+            self.run(move |transaction| {
+                transaction.execute(
+                    "DELETE FROM trash_versions WHERE backup_id IN (
+                         SELECT backup_id FROM trash WHERE deleted_at < ?1
+                     )",
+                    [deleted_before],
+                )?;
+                transaction.execute("DELETE FROM trash WHERE deleted_at < ?1", [deleted_before])
+            })
+            .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKUP_ID: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    async fn read(store: &SqliteStore, range: Range<u64>) -> Option<Vec<u8>> {
+        let backup = store.get_range(BACKUP_ID, range).await.unwrap()?;
+        Some(hyper::body::to_bytes(backup.body).await.unwrap().to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backups.sqlite3");
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(store.get(BACKUP_ID).await.unwrap().is_none());
+        assert!(!store.put(BACKUP_ID, Body::from("antikva")).await.unwrap());
+        assert!(store.put(BACKUP_ID, Body::from("nova")).await.unwrap());
+        assert_eq!(read(&store, 0..4).await.unwrap(), b"nova");
+        assert_eq!(read(&store, 1..3).await.unwrap(), b"ov");
+        let metadata = store.stat(BACKUP_ID).await.unwrap().unwrap();
+        assert_eq!(metadata.size, 4);
+        assert_eq!(
+            metadata.etag.as_deref(),
+            Some(&*hex(&Sha256::digest(b"nova")))
+        );
+        assert_eq!(
+            store.list().await.unwrap(),
+            vec![(BACKUP_ID.into(), metadata)]
+        );
+
+        // The database persists
+        drop(store);
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(store.head(BACKUP_ID).await.unwrap());
+        assert!(store.delete(BACKUP_ID).await.unwrap());
+        assert!(!store.delete(BACKUP_ID).await.unwrap());
+        assert!(store.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let store = SqliteStore::open_in_memory().unwrap().with_versions(2);
+        for body in ["eins", "zwei", "drei", "vier"] {
+            store.put(BACKUP_ID, Body::from(body)).await.unwrap();
+        }
+        let versions = store.versions(BACKUP_ID).await.unwrap();
+        let numbers: Vec<_> = versions.iter().map(|v| v.version).collect();
+        assert_eq!(numbers, vec![2, 3]);
+        assert!(store.restore_version(BACKUP_ID, 2).await.unwrap());
+        assert!(!store.restore_version(BACKUP_ID, 1).await.unwrap());
+        assert_eq!(read(&store, 0..u64::MAX).await.unwrap(), b"zwei");
+        let numbers: Vec<_> = store
+            .versions(BACKUP_ID)
+            .await
+            .unwrap()
+            .iter()
+            .map(|v| v.version)
+            .collect();
+        assert_eq!(numbers, vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_trash() {
+        let store = SqliteStore::open_in_memory().unwrap().with_versions(1);
+        store.put(BACKUP_ID, Body::from("antikva")).await.unwrap();
+        store.put(BACKUP_ID, Body::from("nova")).await.unwrap();
+        assert!(store.trash(BACKUP_ID).await.unwrap());
+        assert!(!store.trash(BACKUP_ID).await.unwrap());
+        assert!(!store.head(BACKUP_ID).await.unwrap());
+        let trash = store.list_trash().await.unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].1.metadata.size, 4);
+
+        assert!(store.untrash(BACKUP_ID).await.unwrap());
+        assert!(!store.untrash(BACKUP_ID).await.unwrap());
+        assert_eq!(read(&store, 0..4).await.unwrap(), b"nova");
+        assert_eq!(store.versions(BACKUP_ID).await.unwrap().len(), 1);
+
+        store.trash(BACKUP_ID).await.unwrap();
+        let now = SystemTime::now();
+        assert_eq!(
+            store
+                .purge_trash(now - Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            store
+                .purge_trash(now + Duration::from_secs(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(store.list_trash().await.unwrap().is_empty());
+    }
+}
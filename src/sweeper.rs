@@ -0,0 +1,268 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use log::{debug, error, info, trace};
+
+use crate::{
+    config::ServerConfig,
+    locks::BackupLocks,
+    metrics::{metrics, Metrics},
+    storage::{BackupMetadata, BackupStore},
+};
+
+/// A source for the current time.
+///
+/// The sweeper uses this instead of calling `SystemTime::now()` directly, so
+/// that expiry can be tested without waiting for backups to actually expire.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The default clock, based on the system time.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The sweeper periodically deletes backups that have not been updated
+/// within the configured retention period.
+pub struct Sweeper {
+    store: Arc<dyn BackupStore>,
+    retention: Duration,
+    orphan_max_age: Duration,
+    trash_retention: Duration,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    locks: Arc<BackupLocks>,
+    metrics: &'static Metrics,
+}
+
+impl Sweeper {
+    pub fn new(config: &ServerConfig, store: Arc<dyn BackupStore>) -> Self {
+        Self {
+            store,
+            retention: Duration::from_secs(u64::from(config.retention_days) * 24 * 3600),
+            orphan_max_age: Duration::from_secs(config.orphan_max_age_secs.unwrap_or(3600)),
+            trash_retention: Duration::from_secs(
+                u64::from(config.trash_days.unwrap_or(0)) * 24 * 3600,
+            ),
+            interval: Duration::from_secs(config.sweep_interval_secs.unwrap_or(3600)),
+            clock: Arc::new(SystemClock),
+            locks: Arc::new(BackupLocks::new()),
+            metrics: metrics(),
+        }
+    }
+
+    /// Replace the clock used to determine whether a backup has expired.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Share the locks of the server, so that backups are not deleted while
+    /// they are being uploaded.
+    pub fn with_locks(mut self, locks: Arc<BackupLocks>) -> Self {
+        self.locks = locks;
+        self
+    }
+
+    /// Replace the metrics that are updated by the sweeper, which are the
+    /// global metrics by default.
+    pub fn with_metrics(mut self, metrics: &'static Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Return whether a backup that was last modified at `modified` has
+    /// expired, along with its age.
+    fn expired(&self, now: SystemTime, modified: SystemTime) -> Option<Duration> {
+        // Backups modified in the future have certainly not expired
+        let age = now.duration_since(modified).ok()?;
+        if age <= self.retention {
+            return None;
+        }
+        Some(age)
+    }
+
+    /// Return the id and age of all expired backups.
+    pub async fn expired_backups(&self) -> anyhow::Result<Vec<(String, Duration)>> {
+        let backups = self.store.list().await?;
+        Ok(self.filter_expired(self.clock.now(), &backups))
+    }
+
+    fn filter_expired(
+        &self,
+        now: SystemTime,
+        backups: &[(String, BackupMetadata)],
+    ) -> Vec<(String, Duration)> {
+        let mut expired = vec![];
+        for (backup_id, metadata) in backups {
+            match self.expired(now, metadata.modified) {
+                Some(age) => expired.push((backup_id.clone(), age)),
+                None => trace!("Backup {} has not expired yet", backup_id),
+            }
+        }
+        expired
+    }
+
+    /// Delete all expired backups once.
+    ///
+    /// Also update the metrics of the number and total size of stored
+    /// backups, since all backups are listed anyway. Return the number of
+    /// backups that were deleted.
+    pub async fn sweep(&self) -> anyhow::Result<usize> {
+        let now = self.clock.now();
+        let backups = self.store.list().await?;
+        let mut count = backups.len() as u64;
+        let mut bytes: u64 = backups.iter().map(|(_, metadata)| metadata.size).sum();
+        let mut deleted = 0;
+        for (backup_id, _) in self.filter_expired(now, &backups) {
+            // A backup that is being modified is about to be refreshed or
+            // deleted anyway
+            let _guard = match self.locks.try_write(&backup_id) {
+                Some(guard) => guard,
+                None => continue,
+            };
+            // The backup might have been uploaded again since it was listed
+            let metadata = match self
+                .store
+                .stat(&backup_id)
+                .await
+                .with_context(|| format!("Could not look up expired backup {}", backup_id))?
+            {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            let age = match self.expired(now, metadata.modified) {
+                Some(age) => age,
+                None => continue,
+            };
+
+            // The backup might have been deleted in the meantime, that's fine
+            if self
+                .store
+                .delete(&backup_id)
+                .await
+                .with_context(|| format!("Could not delete expired backup {}", backup_id))?
+            {
+                info!(
+                    "Deleted expired backup {} (last upload {} days ago)",
+                    backup_id,
+                    age.as_secs() / (24 * 3600)
+                );
+                self.metrics.sweeper_deletions.inc();
+                count = count.saturating_sub(1);
+                bytes = bytes.saturating_sub(metadata.size);
+                deleted += 1;
+            }
+        }
+        self.metrics.backups.set(count);
+        self.metrics.stored_bytes.set(bytes);
+        Ok(deleted)
+    }
+
+    /// Remove the temporary files of uploads that were interrupted by a crash.
+    ///
+    /// Return the number of removed files.
+    pub async fn remove_orphans(&self) -> anyhow::Result<usize> {
+        let modified_before = self
+            .clock
+            .now()
+            .checked_sub(self.orphan_max_age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let removed = self.store.remove_orphans(modified_before).await?;
+        self.metrics.orphans_removed.add(removed as u64);
+        Ok(removed)
+    }
+
+    /// Permanently delete backups whose grace period in the trash is over.
+    ///
+    /// Return the number of purged backups.
+    pub async fn purge_trash(&self) -> anyhow::Result<usize> {
+        let deleted_before = self
+            .clock
+            .now()
+            .checked_sub(self.trash_retention)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let purged = self.store.purge_trash(deleted_before).await?;
+        self.metrics.trash_purged.add(purged as u64);
+        Ok(purged)
+    }
+
+    /// Run the sweeper forever, sweeping once per interval.
+    ///
+    /// The first sweep happens immediately, so that orphaned uploads from a
+    /// previous crash are reclaimed at startup.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            debug!("Sweeping expired backups");
+            match self.sweep().await {
+                Ok(0) => debug!("No expired backups found"),
+                Ok(n) => info!("Deleted {} expired backup(s)", n),
+                Err(e) => error!("Could not sweep expired backups: {:#}", e),
+            }
+            match self.remove_orphans().await {
+                Ok(0) => debug!("No orphaned uploads found"),
+                Ok(n) => info!("Reclaimed {} orphaned upload(s)", n),
+                Err(e) => error!("Could not remove orphaned uploads: {:#}", e),
+            }
+            match self.purge_trash().await {
+                Ok(0) => debug!("No backups to purge from the trash"),
+                Ok(n) => info!("Purged {} backup(s) from the trash", n),
+                Err(e) => error!("Could not purge the trash: {:#}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    struct FutureClock(Duration);
+
+    impl Clock for FutureClock {
+        fn now(&self) -> SystemTime {
+            SystemTime::now() + self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_updates_metrics() {
+        let config: ServerConfig = toml::from_str(
+            "max_backup_bytes = 100\nretention_days = 1\nbackup_dir = \".\"\nlisten_on = \"127.0.0.1:3000\"",
+        )
+        .unwrap();
+        let store = Arc::new(MemoryStore::new());
+        store
+            .put(&"1".repeat(64), vec![1, 2, 3].into())
+            .await
+            .unwrap();
+        store
+            .put(&"2".repeat(64), vec![4, 5, 6, 7].into())
+            .await
+            .unwrap();
+
+        // Other tests update the global metrics concurrently
+        let metrics = Box::leak(Box::default());
+        let sweeper = Sweeper::new(&config, store.clone()).with_metrics(metrics);
+        assert_eq!(sweeper.sweep().await.unwrap(), 0);
+        assert_eq!(metrics.backups.get(), 2);
+        assert_eq!(metrics.stored_bytes.get(), 7);
+
+        let sweeper = sweeper.with_clock(FutureClock(Duration::from_secs(2 * 24 * 3600)));
+        assert_eq!(sweeper.sweep().await.unwrap(), 2);
+        assert_eq!(metrics.backups.get(), 0);
+        assert_eq!(metrics.stored_bytes.get(), 0);
+    }
+}
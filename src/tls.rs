@@ -0,0 +1,202 @@
+//! TLS termination with certificate hot reloading.
+
+use std::{
+    fs,
+    io::{BufReader, Error as IoError},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use futures::{channel::mpsc, SinkExt};
+use hyper::server::accept::{self, Accept};
+use log::{debug, error, info, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        Semaphore,
+    },
+};
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+/// How often the certificate files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The maximum number of TLS handshakes that are done concurrently.
+///
+/// Further connections wait in the listen backlog of the socket.
+const MAX_HANDSHAKES: usize = 256;
+
+/// A TLS acceptor that can be reloaded when the certificate changes.
+///
+/// Reloading only affects new connections, established connections keep
+/// using the previous certificate.
+pub struct ReloadableTlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: RwLock<Arc<TlsAcceptor>>,
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadableTlsAcceptor {
+    /// Load the PEM encoded certificate chain and private key.
+    pub fn new(cert_path: &Path, key_path: &Path) -> anyhow::Result<Self> {
+        let modified = (modified(cert_path), modified(key_path));
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            acceptor: RwLock::new(Arc::new(load_acceptor(cert_path, key_path)?)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reload the certificate and private key.
+    ///
+    /// If loading fails, the previous certificate stays in use.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = (modified(&self.cert_path), modified(&self.key_path));
+        let acceptor = load_acceptor(&self.cert_path, &self.key_path)?;
+        *self
+            .acceptor
+            .write()
+            .expect("TLS acceptor lock is poisoned") = Arc::new(acceptor);
+        *self.modified.lock().expect("TLS acceptor lock is poisoned") = modified;
+        info!("Reloaded TLS certificate from {:?}", self.cert_path);
+        Ok(())
+    }
+
+    /// Return whether the certificate or key file changed since the last (re)load.
+    fn changed(&self) -> bool {
+        let modified = (modified(&self.cert_path), modified(&self.key_path));
+        *self.modified.lock().expect("TLS acceptor lock is poisoned") != modified
+    }
+
+    fn current(&self) -> Arc<TlsAcceptor> {
+        self.acceptor
+            .read()
+            .expect("TLS acceptor lock is poisoned")
+            .clone()
+    }
+
+    /// Reload the certificate whenever `hangup` receives a SIGHUP or when the
+    /// files change, forever.
+    pub async fn watch(self: Arc<Self>, mut hangup: broadcast::Receiver<()>) {
+        let mut hangup_open = true;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                signal = hangup.recv(), if hangup_open => {
+                    if let Err(RecvError::Closed) = signal {
+                        hangup_open = false;
+                        continue;
+                    }
+                    debug!("Reloading TLS certificate after SIGHUP");
+                }
+                _ = interval.tick() => {
+                    if !self.changed() {
+                        continue;
+                    }
+                    debug!("Reloading TLS certificate after file change");
+                }
+            }
+            if let Err(e) = self.reload() {
+                error!("Could not reload TLS certificate: {:#}", e);
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let cert = fs::read(cert_path)
+        .with_context(|| format!("Could not read TLS certificate {:?}", cert_path))?;
+    let key =
+        fs::read(key_path).with_context(|| format!("Could not read TLS key {:?}", key_path))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(&cert[..]))
+        .with_context(|| format!("Could not parse TLS certificate {:?}", cert_path))?;
+    if certs.is_empty() {
+        bail!("No certificate found in {:?}", cert_path);
+    }
+    let key = read_private_key(&key)
+        .with_context(|| format!("Could not parse TLS key {:?}", key_path))?;
+
+    // The safe defaults only allow TLS 1.2 and 1.3
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(Certificate).collect(), key)
+        .context("Could not load TLS certificate and key")?;
+    Ok(Arc::new(config).into())
+}
+
+/// Read the first PKCS#8, traditional RSA or SEC1 EC private key.
+fn read_private_key(pem: &[u8]) -> anyhow::Result<PrivateKey> {
+    let mut reader = BufReader::new(pem);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    bail!("No private key found")
+}
+
+/// Accept TLS connections on the listener.
+///
+/// Handshakes are done concurrently, up to [`MAX_HANDSHAKES`] at a time.
+/// Handshakes that fail or take longer than the timeout are logged and the
+/// connection is dropped.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: Arc<ReloadableTlsAcceptor>,
+    handshake_timeout: Duration,
+) -> impl Accept<Conn = TlsStream<TcpStream>, Error = IoError> {
+    let (tx, rx) = mpsc::channel(MAX_HANDSHAKES);
+    let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+    tokio::spawn(async move {
+        // Stop accepting connections once the server is gone
+        while !tx.is_closed() {
+            let permit = handshakes
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Handshake semaphore is closed");
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Errors like running out of file descriptors are
+                    // usually temporary, so back off a little
+                    warn!("Could not accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let tls_acceptor = acceptor.current();
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(handshake_timeout, tls_acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        let _ = tx.send(Ok(tls_stream)).await;
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                    Err(_) => debug!("TLS handshake with {} timed out", remote_addr),
+                }
+                // Connections waiting for the server count towards the limit as well
+                drop(permit);
+            });
+        }
+    });
+    accept::from_stream(rx)
+}
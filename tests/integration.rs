@@ -1,18 +1,34 @@
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
 use std::env;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::os::unix::fs::PermissionsExt;
-use std::sync::Once;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Once};
 use std::thread;
+use std::time::{Duration, SystemTime};
 
-use hyper::Server;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Server,
+};
+use p256::pkcs8::{EncodePrivateKey, LineEnding};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, PKCS_ECDSA_P256_SHA256};
 use reqwest::{
     blocking::{Client, Response},
     header, Method,
 };
 use tempfile::{self, TempDir};
 
-use sekursranko::{MakeBackupService, ServerConfig};
+use sekursranko::{
+    metrics,
+    storage::{self, FilesystemStore},
+    tls::{self, ReloadableTlsAcceptor},
+    BackupLocks, Clock, FilesystemConfig, MakeBackupService, RateLimitConfig, ReloadableConfig,
+    S3Config, ServerConfig, SqliteConfig, StorageConfig, Sweeper,
+};
 
 static LOGGER_INIT: Once = Once::new();
 
@@ -21,11 +37,22 @@ struct TestServer {
     base_url: String,
     backup_dir: TempDir,
     config: ServerConfig,
+    reloadable_config: Arc<ReloadableConfig>,
 }
 
 impl TestServer {
     /// Create a new test server instance.
     fn new() -> Self {
+        Self::with_storage(None)
+    }
+
+    /// Create a new test server instance with the specified storage backend.
+    fn with_storage(storage: Option<StorageConfig>) -> Self {
+        Self::with_config(|config| config.storage = storage)
+    }
+
+    /// Create a new test server instance, modifying the default config.
+    fn with_config(modify: impl FnOnce(&mut ServerConfig)) -> Self {
         // Initialize logger
         LOGGER_INIT.call_once(|| {
             if env::var("RUST_LOG")
@@ -44,17 +71,34 @@ impl TestServer {
             .expect("Could not create temporary backup directory");
 
         // Create config object
-        let config = ServerConfig {
+        let mut config = ServerConfig {
             max_backup_bytes: 524_288,
             retention_days: 180,
             backup_dir: backup_dir.path().to_path_buf(),
             listen_on: "-integrationtest-".to_string(),
             allow_browser: None,
+            sweep_interval_secs: None,
+            storage: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_handshake_timeout_secs: None,
+            rate_limit: None,
+            metrics_listen_on: None,
+            min_free_bytes: None,
+            shutdown_timeout_secs: None,
+            orphan_max_age_secs: None,
+            keep_versions: None,
+            trash_days: None,
+            admin_token: None,
+            encryption_key_file: None,
         };
+        modify(&mut config);
 
         // Run server
         let addr = ([127, 0, 0, 1], 0).into();
-        let service = MakeBackupService::new(config.clone());
+        let store = storage::from_config(&config).unwrap();
+        let service = MakeBackupService::new(config.clone(), store);
+        let reloadable_config = service.config();
         let (port_tx, port_rx) = std::sync::mpsc::channel();
         let handle = thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
@@ -82,6 +126,7 @@ impl TestServer {
             base_url,
             backup_dir,
             config,
+            reloadable_config,
         }
     }
 }
@@ -223,11 +268,152 @@ fn backup_download_ok() {
         .send()
         .unwrap();
     assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "10");
+    assert_eq!(res.headers()[header::ACCEPT_RANGES], "bytes");
     let text = res.text().unwrap();
     println!("{}", text);
     assert_eq!(text, "tre sekura");
 }
 
+#[test]
+fn backup_download_large() {
+    let TestServer {
+        base_url,
+        backup_dir,
+        ..
+    } = TestServer::new();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let mut file = File::create(backup_dir.path().join(backup_id)).unwrap();
+    file.write_all(&data).unwrap();
+    let res = Client::new()
+        .get(format!("{}/backups/{}", base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "200000");
+    assert_eq!(res.bytes().unwrap().as_ref(), &data[..]);
+}
+
+#[test]
+fn backup_download_range() {
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    for storage in [None, Some(StorageConfig::Memory)] {
+        let server = TestServer::with_storage(storage);
+        let url = format!("{}/backups/{}", server.base_url, "9".repeat(64));
+        let res = Client::new()
+            .put(&url)
+            .header(header::USER_AGENT, "Threema")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(data.clone())
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 201);
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        let get = |range: &str, if_range: Option<&str>| {
+            let mut builder = Client::new()
+                .get(&url)
+                .header(header::USER_AGENT, "Threema")
+                .header(header::ACCEPT, "application/octet-stream")
+                .header(header::RANGE, range);
+            if let Some(if_range) = if_range {
+                builder = builder.header(header::IF_RANGE, if_range);
+            }
+            builder.send().unwrap()
+        };
+        let assert_partial = |res: reqwest::blocking::Response, start: usize, end: usize| {
+            assert_eq!(res.status().as_u16(), 206);
+            assert_eq!(res.headers()[header::ACCEPT_RANGES], "bytes");
+            assert_eq!(
+                res.headers()[header::CONTENT_RANGE],
+                format!("bytes {}-{}/200000", start, end - 1).as_str()
+            );
+            assert_eq!(
+                res.headers()[header::CONTENT_LENGTH],
+                (end - start).to_string().as_str()
+            );
+            assert_eq!(res.bytes().unwrap().as_ref(), &data[start..end]);
+        };
+
+        assert_partial(get("bytes=0-99", None), 0, 100);
+        // Spans several chunks of the filesystem backend
+        assert_partial(get("bytes=60000-140000", None), 60000, 140001);
+        assert_partial(get("bytes=199000-", None), 199_000, 200_000);
+        assert_partial(get("bytes=-10", None), 199_990, 200_000);
+        assert_partial(get("bytes=150000-999999", None), 150_000, 200_000);
+        assert_partial(get("bytes=0-9", Some(&etag)), 0, 10);
+
+        // Unsatisfiable range
+        let res = get("bytes=200000-", None);
+        assert_eq!(res.status().as_u16(), 416);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */200000");
+
+        // Ranges that are ignored
+        for (range, if_range) in [
+            ("bytes=0-1,5-6", None),
+            ("lines=0-1", None),
+            ("bytes=0-9", Some("\"outdated\"")),
+        ] {
+            let res = get(range, if_range);
+            assert_eq!(res.status().as_u16(), 200);
+            assert_eq!(res.headers()[header::CONTENT_LENGTH], "200000");
+            assert_eq!(res.bytes().unwrap().as_ref(), &data[..]);
+        }
+    }
+}
+
+#[test]
+fn backup_encrypted_at_rest() {
+    let key_dir = tempfile::tempdir().unwrap();
+    let key_file = key_dir.path().join("keys");
+    std::fs::write(
+        &key_file,
+        "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n",
+    )
+    .unwrap();
+    let server = TestServer::with_config(|config| {
+        config.encryption_key_file = Some(key_file.clone());
+    });
+    let backup_id = "a".repeat(64);
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let res = upload_backup(&server.base_url, &backup_id, data.clone());
+    assert_eq!(res.status().as_u16(), 201);
+
+    // Neither the contents nor the exact size are stored
+    let on_disk = std::fs::read(server.backup_dir.path().join(&backup_id)).unwrap();
+    assert!(on_disk.len() > data.len());
+    assert!(!on_disk.windows(100).any(|window| window == &data[..100]));
+
+    let url = format!("{}/backups/{}", server.base_url, backup_id);
+    let res = Client::new()
+        .get(&url)
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "100000");
+    assert_eq!(res.bytes().unwrap().as_ref(), &data[..]);
+
+    let res = Client::new()
+        .get(&url)
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .header(header::RANGE, "bytes=70000-")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 206);
+    assert_eq!(res.bytes().unwrap().as_ref(), &data[70000..]);
+
+    // Only the filesystem backend supports encryption
+    let mut config = server.config.clone();
+    config.storage = Some(StorageConfig::Memory);
+    assert!(storage::from_config(&config).is_err());
+}
+
 #[test]
 fn backup_download_present_head() {
     let TestServer {
@@ -245,6 +431,7 @@ fn backup_download_present_head() {
         .send()
         .unwrap();
     assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "10");
     let text = res.text().unwrap();
     println!("{}", text);
     assert_eq!(text, "");
@@ -484,3 +671,1303 @@ fn backup_delete_success() {
     // Ensure file was deleted
     assert!(!backup_file_path.exists());
 }
+
+/// A clock that is a fixed duration ahead of the system time.
+struct FutureClock(Duration);
+
+impl Clock for FutureClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now() + self.0
+    }
+}
+
+fn sweep(config: &ServerConfig, clock: FutureClock) -> usize {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let store = storage::from_config(config).unwrap();
+    rt.block_on(Sweeper::new(config, store).with_clock(clock).sweep())
+        .expect("Sweep failed")
+}
+
+/// Backups within the retention period are kept.
+#[test]
+fn sweeper_keeps_recent_backups() {
+    let TestServer {
+        backup_dir, config, ..
+    } = TestServer::new();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let backup_file_path = backup_dir.path().join(backup_id);
+    File::create(&backup_file_path).unwrap();
+
+    let deleted = sweep(&config, FutureClock(Duration::from_secs(179 * 24 * 3600)));
+    assert_eq!(deleted, 0);
+    assert!(backup_file_path.exists());
+}
+
+/// Backups outside the retention period are deleted, other files are ignored.
+#[test]
+fn sweeper_deletes_expired_backups() {
+    let TestServer {
+        backup_dir, config, ..
+    } = TestServer::new();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let backup_file_path = backup_dir.path().join(backup_id);
+    File::create(&backup_file_path).unwrap();
+    let other_file_path = backup_dir.path().join("README");
+    File::create(&other_file_path).unwrap();
+
+    let deleted = sweep(&config, FutureClock(Duration::from_secs(181 * 24 * 3600)));
+    assert_eq!(deleted, 1);
+    assert!(!backup_file_path.exists());
+    assert!(other_file_path.exists());
+}
+
+/// Backups that are being modified are not deleted by the sweeper.
+#[test]
+fn sweeper_skips_locked_backups() {
+    let TestServer {
+        backup_dir, config, ..
+    } = TestServer::new();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let backup_file_path = backup_dir.path().join(backup_id);
+    File::create(&backup_file_path).unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let locks = Arc::new(BackupLocks::new());
+    let sweep = || {
+        let store = storage::from_config(&config).unwrap();
+        let clock = FutureClock(Duration::from_secs(181 * 24 * 3600));
+        rt.block_on(
+            Sweeper::new(&config, store)
+                .with_clock(clock)
+                .with_locks(locks.clone())
+                .sweep(),
+        )
+        .expect("Sweep failed")
+    };
+
+    let guard = locks.try_write(backup_id).unwrap();
+    assert_eq!(sweep(), 0);
+    assert!(backup_file_path.exists());
+    drop(guard);
+
+    assert_eq!(sweep(), 1);
+    assert!(!backup_file_path.exists());
+}
+
+/// Deleted backups are purged from the trash after the grace period.
+#[test]
+fn sweeper_purges_trash() {
+    let TestServer {
+        base_url,
+        backup_dir,
+        mut config,
+        ..
+    } = TestServer::with_config(|config| config.trash_days = Some(7));
+    let backup_url = format!("{}/backups/{}", base_url, "d".repeat(64));
+    for method in [Method::PUT, Method::DELETE] {
+        let res = Client::new()
+            .request(method, &backup_url)
+            .header(header::USER_AGENT, "Threema")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body("sekurkopio")
+            .send()
+            .unwrap();
+        assert!(res.status().is_success());
+    }
+    let trash_path = backup_dir.path().join("trash").join("d".repeat(64));
+    assert!(trash_path.join("backup").exists());
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let purge_trash = |config: &ServerConfig, clock| {
+        let store = storage::from_config(config).unwrap();
+        rt.block_on(Sweeper::new(config, store).with_clock(clock).purge_trash())
+            .expect("Purging the trash failed")
+    };
+
+    let six_days = FutureClock(Duration::from_secs(6 * 24 * 3600));
+    assert_eq!(purge_trash(&config, six_days), 0);
+    assert!(trash_path.exists());
+    let eight_days = FutureClock(Duration::from_secs(8 * 24 * 3600));
+    assert_eq!(purge_trash(&config, eight_days), 1);
+    assert!(!trash_path.exists());
+
+    // Disabling the trash empties it
+    let res = Client::new()
+        .put(&backup_url)
+        .header(header::USER_AGENT, "Threema")
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body("sekurkopio")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 201);
+    let res = Client::new()
+        .delete(&backup_url)
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 204);
+    config.trash_days = None;
+    assert_eq!(purge_trash(&config, FutureClock(Duration::from_secs(1))), 1);
+    assert!(!trash_path.exists());
+}
+
+/// Temporary files of interrupted uploads are removed once they are old enough.
+#[test]
+fn sweeper_removes_orphaned_uploads() {
+    let TestServer {
+        backup_dir, config, ..
+    } = TestServer::new();
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let temp_file_path = backup_dir.path().join(format!("{}.Ab3dE5gH9j", backup_id));
+    File::create(&temp_file_path).unwrap();
+    let other_file_path = backup_dir.path().join(format!("{}.txt", backup_id));
+    File::create(&other_file_path).unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let remove_orphans = |clock| {
+        let store = storage::from_config(&config).unwrap();
+        rt.block_on(
+            Sweeper::new(&config, store)
+                .with_clock(clock)
+                .remove_orphans(),
+        )
+        .expect("Removing orphans failed")
+    };
+
+    assert_eq!(remove_orphans(FutureClock(Duration::from_secs(60))), 0);
+    assert!(temp_file_path.exists());
+
+    assert_eq!(remove_orphans(FutureClock(Duration::from_secs(3601))), 1);
+    assert!(!temp_file_path.exists());
+    assert!(other_file_path.exists());
+}
+
+/// The memory backend behaves like the filesystem backend.
+#[test]
+fn memory_storage_backup_lifecycle() {
+    let TestServer {
+        base_url,
+        backup_dir,
+        config,
+        ..
+    } = TestServer::with_storage(Some(StorageConfig::Memory));
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let download = || {
+        Client::new()
+            .get(format!("{}/backups/{}", base_url, backup_id))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .unwrap()
+    };
+
+    // Create and update
+    let res = upload_backup(&base_url, backup_id, b"sekurkopio antikva".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    let res = upload_backup(&base_url, backup_id, b"sekurkopio nova".to_vec());
+    assert_eq!(res.status().as_u16(), 204);
+    let res = upload_backup(
+        &base_url,
+        backup_id,
+        vec![0; config.max_backup_bytes as usize + 1],
+    );
+    assert_eq!(res.status().as_u16(), 413);
+
+    // Nothing is written to the backup directory
+    assert_eq!(backup_dir.path().read_dir().unwrap().count(), 0);
+
+    // Download
+    let res = download();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.text().unwrap(), "sekurkopio nova");
+
+    // Delete
+    let res = Client::new()
+        .delete(format!("{}/backups/{}", base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 204);
+    assert_eq!(download().status().as_u16(), 404);
+}
+
+#[test]
+fn sqlite_storage_backup_lifecycle() {
+    let TestServer {
+        base_url,
+        backup_dir,
+        config,
+        ..
+    } = TestServer::with_storage(Some(StorageConfig::Sqlite(SqliteConfig::default())));
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let download = || {
+        Client::new()
+            .get(format!("{}/backups/{}", base_url, backup_id))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .unwrap()
+    };
+
+    // Create and update
+    let res = upload_backup(&base_url, backup_id, b"sekurkopio antikva".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    let res = upload_backup(&base_url, backup_id, b"sekurkopio nova".to_vec());
+    assert_eq!(res.status().as_u16(), 204);
+    let res = upload_backup(
+        &base_url,
+        backup_id,
+        vec![0; config.max_backup_bytes as usize + 1],
+    );
+    assert_eq!(res.status().as_u16(), 413);
+
+    // All backups are stored in the database file
+    let files: Vec<_> = backup_dir
+        .path()
+        .read_dir()
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(files.contains(&"backups.sqlite3".into()), "{:?}", files);
+    assert!(!files.contains(&backup_id.into()));
+
+    // Download
+    let res = download();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.text().unwrap(), "sekurkopio nova");
+    let res = Client::new()
+        .get(format!("{}/backups/{}", base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .header(header::RANGE, "bytes=11-")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 206);
+    assert_eq!(res.text().unwrap(), "nova");
+
+    // Delete
+    let res = Client::new()
+        .delete(format!("{}/backups/{}", base_url, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 204);
+    assert_eq!(download().status().as_u16(), 404);
+}
+
+/// Start a minimal S3 compatible server that keeps objects in memory.
+///
+/// All objects are reported as last modified on 2000-01-01. Return the
+/// endpoint URL.
+fn start_mock_s3() -> String {
+    let objects: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let make_service = make_service_fn(move |_| {
+        let objects = objects.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                let objects = objects.clone();
+                async move {
+                    let response = hyper::Response::builder();
+                    let authorized = req
+                        .headers()
+                        .get("authorization")
+                        .and_then(|v| v.to_str().ok())
+                        .is_some_and(|v| v.starts_with("AWS4-HMAC-SHA256 Credential=mock-id/"));
+                    if !authorized {
+                        return response.status(403).body(Body::empty());
+                    }
+                    let path = req.uri().path().to_string();
+                    let key = match path.strip_prefix("/bucket/") {
+                        Some(key) => key.to_string(),
+                        None if path == "/bucket" => {
+                            let query = req.uri().query().unwrap_or("");
+                            assert!(query.contains("list-type=2"));
+                            assert!(query.contains("prefix=backups%2F"));
+                            let contents: String = objects
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .map(|(key, bytes)| {
+                                    format!(
+                                        "<Contents><Key>{}</Key>\
+                                         <LastModified>2000-01-01T00:00:00.000Z</LastModified>\
+                                         <Size>{}</Size></Contents>",
+                                        key,
+                                        bytes.len()
+                                    )
+                                })
+                                .collect();
+                            return response.status(200).body(Body::from(format!(
+                                "<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                                contents
+                            )));
+                        }
+                        None => return response.status(404).body(Body::empty()),
+                    };
+                    let response =
+                        response.header("last-modified", "Sat, 01 Jan 2000 00:00:00 GMT");
+                    match *req.method() {
+                        Method::GET | Method::HEAD => match objects.lock().unwrap().get(&key) {
+                            Some(bytes) => response
+                                .status(200)
+                                .header("content-length", bytes.len())
+                                .body(Body::from(bytes.clone())),
+                            None => response.status(404).body(Body::empty()),
+                        },
+                        Method::PUT => {
+                            let bytes = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            objects.lock().unwrap().insert(key, bytes.to_vec());
+                            response.status(200).body(Body::empty())
+                        }
+                        Method::DELETE => {
+                            objects.lock().unwrap().remove(&key);
+                            response.status(204).body(Body::empty())
+                        }
+                        _ => response.status(405).body(Body::empty()),
+                    }
+                }
+            }))
+        }
+    });
+
+    let addr = ([127, 0, 0, 1], 0).into();
+    let (port_tx, port_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let server = Server::bind(&addr).serve(make_service);
+            port_tx.send(server.local_addr().port()).unwrap();
+            server.await.unwrap();
+        });
+    });
+    format!("http://127.0.0.1:{}", port_rx.recv().unwrap())
+}
+
+/// The S3 backend stores backups in the bucket and computes retention from
+/// the object modification time.
+#[test]
+fn s3_storage_backup_lifecycle() {
+    let TestServer {
+        base_url,
+        backup_dir,
+        config,
+        ..
+    } = TestServer::with_storage(Some(StorageConfig::S3(S3Config {
+        endpoint: start_mock_s3(),
+        bucket: "bucket".to_string(),
+        prefix: Some("backups/".to_string()),
+        region: None,
+        access_key_id: "mock-id".to_string(),
+        secret_access_key: "mock-secret".to_string(),
+    })));
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let download = || {
+        Client::new()
+            .get(format!("{}/backups/{}", base_url, backup_id))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .unwrap()
+    };
+
+    // Create and update
+    let res = upload_backup(&base_url, backup_id, b"sekurkopio antikva".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    let res = upload_backup(&base_url, backup_id, b"sekurkopio nova".to_vec());
+    assert_eq!(res.status().as_u16(), 204);
+    assert_eq!(backup_dir.path().read_dir().unwrap().count(), 0);
+
+    // Download
+    let res = download();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.text().unwrap(), "sekurkopio nova");
+
+    // The object was last modified in 2000, so it has long expired
+    assert_eq!(sweep(&config, FutureClock(Duration::from_secs(0))), 1);
+    assert_eq!(download().status().as_u16(), 404);
+}
+
+/// Backups in the flat layout are still served in the sharded layout and can
+/// be migrated.
+#[test]
+fn filesystem_sharded_migration() {
+    let fs_config = FilesystemConfig {
+        sharded: Some(true),
+        ..Default::default()
+    };
+    let TestServer {
+        base_url,
+        backup_dir,
+        ..
+    } = TestServer::with_storage(Some(StorageConfig::Filesystem(fs_config.clone())));
+    let download = |backup_id: &str| {
+        Client::new()
+            .get(format!("{}/backups/{}", base_url, backup_id))
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .send()
+            .unwrap()
+    };
+
+    // New uploads go to the sharded layout
+    let new_id = "abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789";
+    let res = upload_backup(&base_url, new_id, b"nova".to_vec());
+    assert_eq!(res.status().as_u16(), 201);
+    assert!(backup_dir.path().join("ab/cd").join(new_id).is_file());
+
+    // Existing backups in the flat layout are found
+    let old_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    let mut file = File::create(backup_dir.path().join(old_id)).unwrap();
+    file.write_all(b"antikva").unwrap();
+    assert_eq!(download(old_id).text().unwrap(), "antikva");
+
+    // Migrate, twice to ensure that it can be resumed
+    let store = FilesystemStore::new(backup_dir.path(), &fs_config);
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(rt.block_on(store.migrate_to_sharded()).unwrap(), 1);
+    assert_eq!(rt.block_on(store.migrate_to_sharded()).unwrap(), 0);
+    assert!(!backup_dir.path().join(old_id).exists());
+    assert!(backup_dir.path().join("01/23").join(old_id).is_file());
+    assert_eq!(download(old_id).text().unwrap(), "antikva");
+    assert_eq!(download(new_id).text().unwrap(), "nova");
+
+    // A flat backup never replaces one that was uploaded to the sharded layout
+    let flat_path = backup_dir.path().join(new_id);
+    std::fs::write(&flat_path, b"malnova").unwrap();
+    assert_eq!(rt.block_on(store.migrate_to_sharded()).unwrap(), 0);
+    assert!(flat_path.is_file());
+    assert_eq!(download(new_id).text().unwrap(), "nova");
+}
+
+/// Run the server binary with a config file for the backup directory.
+fn run_cli(backup_dir: &Path, args: &[&str]) -> std::process::Output {
+    let config_path = backup_dir.join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "max_backup_bytes = 524288\nretention_days = 180\nbackup_dir = {:?}\n\
+             listen_on = \"127.0.0.1:0\"\ntrash_days = 7\n",
+            backup_dir
+        ),
+    )
+    .unwrap();
+    run_cli_with_config(&config_path, args)
+}
+
+fn run_cli_with_config(config_path: &Path, args: &[&str]) -> std::process::Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_sekursranko"))
+        .arg("--config")
+        .arg(config_path)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn cli_inspect_and_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    let recent_id = "1".repeat(64);
+    let expired_id = "2".repeat(64);
+    let days_ago = |days: u64| SystemTime::now() - Duration::from_secs(days * 24 * 3600);
+    let mut file = File::create(dir.path().join(&recent_id)).unwrap();
+    file.write_all(b"nova").unwrap();
+    file.set_modified(days_ago(10) - Duration::from_secs(3600))
+        .unwrap();
+    let file = File::create(dir.path().join(&expired_id)).unwrap();
+    file.set_modified(days_ago(200)).unwrap();
+    drop(file);
+    let stdout = |output: &std::process::Output| String::from_utf8(output.stdout.clone()).unwrap();
+
+    let output = run_cli(dir.path(), &["list"]);
+    assert!(output.status.success());
+    let listing = stdout(&output);
+    assert!(listing.contains(&recent_id));
+    assert!(listing.contains("10d"));
+    assert!(listing.contains("169d"));
+    assert!(listing.contains(&expired_id));
+    assert!(listing.contains("200d"));
+    assert!(listing.contains("2 backup(s), 1 expired"));
+
+    let output = run_cli(dir.path(), &["stat", &recent_id]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Size:        4 bytes"));
+    assert!(!run_cli(dir.path(), &["stat", &"3".repeat(64)])
+        .status
+        .success());
+    assert!(!run_cli(dir.path(), &["stat", "../config.toml"])
+        .status
+        .success());
+
+    // A dry run keeps the expired backup
+    let output = run_cli(dir.path(), &["purge-expired", "--dry-run"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains(&expired_id));
+    assert!(!stdout(&output).contains(&recent_id));
+    assert!(dir.path().join(&expired_id).exists());
+    let output = run_cli(dir.path(), &["purge-expired"]);
+    assert!(stdout(&output).contains("Deleted 1 expired backup(s)"));
+    assert!(!dir.path().join(&expired_id).exists());
+
+    // Deletions use the trash, unless they are permanent
+    assert!(run_cli(dir.path(), &["delete", &recent_id])
+        .status
+        .success());
+    assert!(!dir.path().join(&recent_id).exists());
+    assert!(dir.path().join("trash").join(&recent_id).exists());
+    assert!(!run_cli(dir.path(), &["delete", &recent_id])
+        .status
+        .success());
+    std::fs::write(dir.path().join(&recent_id), b"nova").unwrap();
+    assert!(run_cli(dir.path(), &["delete", "--permanent", &recent_id])
+        .status
+        .success());
+    assert!(!dir.path().join(&recent_id).exists());
+}
+
+/// Write a self-signed certificate and its private key to `cert.pem` and
+/// `key.pem` in the directory.
+fn write_self_signed_cert(dir: &Path, common_name: &str) {
+    let key = p256::SecretKey::random(&mut rand::rngs::OsRng);
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.key_pair = Some(KeyPair::from_pem(&key.to_pkcs8_pem(LineEnding::LF).unwrap()).unwrap());
+    let cert = rcgen::Certificate::from_params(params).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+    // Use the traditional EC key format, it must be converted to PKCS#8
+    std::fs::write(
+        dir.join("key.pem"),
+        key.to_sec1_pem(LineEnding::LF).unwrap(),
+    )
+    .unwrap();
+}
+
+/// Accepts any server certificate.
+struct AcceptAnyCert;
+
+impl rustls::client::ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _: &rustls::Certificate,
+        _: &[rustls::Certificate],
+        _: &rustls::ServerName,
+        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &[u8],
+        _: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Open a TLS connection and return it along with the server certificate.
+fn tls_connect(
+    port: u16,
+) -> (
+    rustls::StreamOwned<rustls::ClientConnection, TcpStream>,
+    rustls::Certificate,
+) {
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+        .with_no_client_auth();
+    let connection = rustls::ClientConnection::new(
+        Arc::new(config),
+        rustls::ServerName::try_from("localhost").unwrap(),
+    )
+    .unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = rustls::StreamOwned::new(connection, stream);
+    while stream.conn.is_handshaking() {
+        stream
+            .conn
+            .complete_io(&mut stream.sock)
+            .expect("TLS handshake failed");
+    }
+    let cert = stream.conn.peer_certificates().unwrap()[0].clone();
+    (stream, cert)
+}
+
+/// Return the certificate in the `cert.pem` of the directory.
+fn read_cert(dir: &Path) -> rustls::Certificate {
+    let pem = std::fs::read(dir.join("cert.pem")).unwrap();
+    let certs = rustls_pemfile::certs(&mut &pem[..]).unwrap();
+    rustls::Certificate(certs[0].clone())
+}
+
+/// Serve over TLS and reload the certificate without dropping connections.
+#[test]
+fn tls_serve_and_reload() {
+    let TestServer {
+        backup_dir, config, ..
+    } = TestServer::new();
+    let cert_dir = tempfile::tempdir().unwrap();
+    write_self_signed_cert(cert_dir.path(), "unua");
+    let acceptor = Arc::new(
+        ReloadableTlsAcceptor::new(
+            &cert_dir.path().join("cert.pem"),
+            &cert_dir.path().join("key.pem"),
+        )
+        .unwrap(),
+    );
+
+    // Run TLS server
+    let service = MakeBackupService::new(config.clone(), storage::from_config(&config).unwrap());
+    let server_acceptor = acceptor.clone();
+    let (port_tx, port_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            port_tx.send(listener.local_addr().unwrap().port()).unwrap();
+            Server::builder(tls::incoming(
+                listener,
+                server_acceptor,
+                Duration::from_secs(1),
+            ))
+            .serve(service)
+            .await
+            .unwrap();
+        });
+    });
+    let port = port_rx.recv().unwrap();
+
+    // Regular HTTPS request
+    let backup_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    File::create(backup_dir.path().join(backup_id))
+        .unwrap()
+        .write_all(b"tre sekura")
+        .unwrap();
+    let res = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap()
+        .get(format!("https://127.0.0.1:{}/backups/{}", port, backup_id))
+        .header(header::USER_AGENT, "Threema")
+        .header(header::ACCEPT, "application/octet-stream")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.text().unwrap(), "tre sekura");
+
+    // Reload the certificate while a connection is open
+    let first_cert = read_cert(cert_dir.path());
+    let (mut established, cert) = tls_connect(port);
+    assert_eq!(cert, first_cert);
+    write_self_signed_cert(cert_dir.path(), "dua");
+    acceptor.reload().unwrap();
+    let (_, cert) = tls_connect(port);
+    assert_ne!(cert, first_cert);
+    assert_eq!(cert, read_cert(cert_dir.path()));
+
+    // The established connection still works
+    established
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUser-Agent: Threema\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    established.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    // Clients that never start the handshake are disconnected after the timeout
+    let mut idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let started = SystemTime::now();
+    assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+    assert!(started.elapsed().unwrap() >= Duration::from_millis(900));
+}
+
+#[test]
+fn backup_upload_rate_limited() {
+    let server = TestServer::with_config(|config| {
+        config.rate_limit = Some(RateLimitConfig {
+            upload_per_minute: Some(1),
+            ..Default::default()
+        })
+    });
+    let backup_id = "6".repeat(64);
+
+    let res = upload_backup(&server.base_url, &backup_id, vec![1, 2, 3]);
+    assert_eq!(res.status().as_u16(), 201);
+
+    let res = upload_backup(&server.base_url, &backup_id, vec![1, 2, 3]);
+    assert_eq!(res.status().as_u16(), 429);
+    let retry_after: u64 = res.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+    assert_eq!(res.text().unwrap(), "{\"detail\": \"Too many requests\"}");
+}
+
+#[test]
+fn backup_upload_invalid_id_not_rate_limited() {
+    let server = TestServer::with_config(|config| {
+        config.rate_limit = Some(RateLimitConfig {
+            upload_per_minute: Some(1),
+            per_backup_id: Some(true),
+            ..Default::default()
+        })
+    });
+
+    // Invalid ids are rejected without using up the budget
+    for _ in 0..3 {
+        let res = upload_backup(&server.base_url, "invalid", vec![1, 2, 3]);
+        assert_eq!(res.status().as_u16(), 400);
+    }
+    let res = upload_backup(&server.base_url, &"7".repeat(64), vec![1, 2, 3]);
+    assert_eq!(res.status().as_u16(), 201);
+}
+
+#[test]
+fn metrics_endpoint() {
+    let server = TestServer::new();
+    let backup_id = "7".repeat(64);
+    let res = upload_backup(&server.base_url, &backup_id, vec![1, 2, 3, 4]);
+    assert_eq!(res.status().as_u16(), 201);
+
+    let (port_tx, port_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            port_tx.send(listener.local_addr().unwrap().port()).unwrap();
+            metrics::serve(listener).await.unwrap();
+        });
+    });
+    let metrics_url = format!("http://127.0.0.1:{}", port_rx.recv().unwrap());
+
+    let res = Client::new()
+        .get(format!("{}/metrics", metrics_url))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let text = res.text().unwrap();
+    assert!(
+        text.contains("sekursranko_requests_total{route=\"backup\",method=\"PUT\",status=\"201\"}")
+    );
+    assert!(
+        text.contains("sekursranko_request_duration_seconds_bucket{route=\"backup\",le=\"+Inf\"}")
+    );
+    // The totals are counted by the sweeper
+    assert!(text.contains("\nsekursranko_backups "));
+    assert!(text.contains("\nsekursranko_stored_bytes "));
+    assert!(text.contains("\nsekursranko_upload_bytes_total "));
+    assert!(text.contains("\nsekursranko_sweeper_deletions_total "));
+
+    let res = Client::new().get(&metrics_url).send().unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+#[test]
+fn healthz_without_user_agent() {
+    let TestServer { base_url, .. } = TestServer::new();
+    let res = Client::new()
+        .get(format!("{}/healthz", base_url))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.text().unwrap(), "{\"status\": \"ok\"}");
+}
+
+#[test]
+fn readyz() {
+    let server = TestServer::with_config(|config| {
+        config.admin_token = Some("sekreta".to_string());
+    });
+    let readyz = || {
+        let res = Client::new()
+            .get(format!("{}/readyz", server.base_url))
+            .send()
+            .unwrap();
+        (res.status().as_u16(), res.text().unwrap())
+    };
+    let readiness = || {
+        let res = Client::new()
+            .get(format!("{}/admin/readiness", server.base_url))
+            .bearer_auth("sekreta")
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 200);
+        serde_json::from_str::<serde_json::Value>(&res.text().unwrap()).unwrap()
+    };
+
+    // The public endpoint does not reveal any details
+    assert_eq!(readyz(), (200, "{\"status\": \"ok\"}".to_string()));
+    let body = readiness();
+    assert_eq!(body["ready"], true);
+    for check in &["backup_dir", "free_space", "storage"] {
+        assert_eq!(body["checks"][check]["ok"], true, "{}", check);
+    }
+    let res = Client::new()
+        .get(format!("{}/admin/readiness", server.base_url))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+
+    // Not ready without a backup directory
+    std::fs::remove_dir(server.backup_dir.path()).unwrap();
+    assert_eq!(readyz(), (503, "{\"status\": \"unavailable\"}".to_string()));
+    let body = readiness();
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["checks"]["backup_dir"]["ok"], false);
+}
+
+#[test]
+fn backup_conditional_requests() {
+    let server = TestServer::new();
+    let url = format!("{}/backups/{}", server.base_url, "8".repeat(64));
+    let request = |method, headers: &[(header::HeaderName, &str)], body: &'static [u8]| {
+        let mut builder = Client::new()
+            .request(method, &url)
+            .header(header::USER_AGENT, "Threema")
+            .header(header::ACCEPT, "application/octet-stream")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(body);
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder.send().unwrap()
+    };
+
+    // Create only if it does not exist yet
+    let res = request(Method::PUT, &[(header::IF_NONE_MATCH, "*")], b"unua");
+    assert_eq!(res.status().as_u16(), 201);
+    let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+    assert_eq!(
+        etag,
+        "\"244904bff48526e209b0cc3b63bfa5571713f381b90dda9f1b87bd9e08d55512\""
+    );
+    let res = request(Method::PUT, &[(header::IF_NONE_MATCH, "*")], b"unua");
+    assert_eq!(res.status().as_u16(), 412);
+
+    // Download with validators
+    let res = request(Method::GET, &[], b"");
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()[header::ETAG], etag.as_str());
+    let last_modified = res.headers()[header::LAST_MODIFIED]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let res = request(Method::GET, &[(header::IF_NONE_MATCH, &etag)], b"");
+    assert_eq!(res.status().as_u16(), 304);
+    assert_eq!(res.text().unwrap(), "");
+    let res = request(Method::HEAD, &[(header::IF_NONE_MATCH, "\"other\"")], b"");
+    assert_eq!(res.status().as_u16(), 200);
+    let res = request(
+        Method::GET,
+        &[(header::IF_MODIFIED_SINCE, &last_modified)],
+        b"",
+    );
+    assert_eq!(res.status().as_u16(), 304);
+
+    // Optimistic concurrency for updates and deletions
+    let res = request(Method::PUT, &[(header::IF_MATCH, "\"other\"")], b"dua");
+    assert_eq!(res.status().as_u16(), 412);
+    let res = request(Method::PUT, &[(header::IF_MATCH, &etag)], b"dua");
+    assert_eq!(res.status().as_u16(), 204);
+    let new_etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+    assert_ne!(new_etag, etag);
+    let res = request(Method::DELETE, &[(header::IF_MATCH, &etag)], b"");
+    assert_eq!(res.status().as_u16(), 412);
+    let res = request(Method::DELETE, &[(header::IF_MATCH, &new_etag)], b"");
+    assert_eq!(res.status().as_u16(), 204);
+    let res = request(Method::DELETE, &[(header::IF_MATCH, "*")], b"");
+    assert_eq!(res.status().as_u16(), 412);
+}
+
+#[test]
+fn admin_requires_token() {
+    let url_path = format!("/admin/backups/{}/versions", "a".repeat(64));
+
+    // Disabled without a token
+    let server = TestServer::new();
+    let res = Client::new()
+        .get(format!("{}{}", server.base_url, url_path))
+        .bearer_auth("sekreto")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    let server = TestServer::with_config(|config| config.admin_token = Some("sekreto".into()));
+    for token in [None, Some("malĝusta"), Some("sekret")] {
+        let mut builder = Client::new().get(format!("{}{}", server.base_url, url_path));
+        if let Some(token) = token {
+            builder = builder.bearer_auth(token);
+        }
+        let res = builder.send().unwrap();
+        assert_eq!(res.status().as_u16(), 401);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+    let res = Client::new()
+        .get(format!("{}{}", server.base_url, url_path))
+        .bearer_auth("sekreto")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+#[test]
+fn admin_versions_restore() {
+    for storage in [None, Some(StorageConfig::Memory)] {
+        let server = TestServer::with_config(|config| {
+            config.storage = storage;
+            config.keep_versions = Some(2);
+            config.admin_token = Some("sekreto".into());
+        });
+        let backup_url = format!("{}/backups/{}", server.base_url, "b".repeat(64));
+        let versions_url = format!(
+            "{}/admin/backups/{}/versions",
+            server.base_url,
+            "b".repeat(64)
+        );
+        let upload = |body: &'static str| {
+            let res = Client::new()
+                .put(&backup_url)
+                .header(header::USER_AGENT, "Threema")
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(body)
+                .send()
+                .unwrap();
+            assert!(res.status().is_success());
+        };
+        let download = || {
+            Client::new()
+                .get(&backup_url)
+                .header(header::USER_AGENT, "Threema")
+                .header(header::ACCEPT, "application/octet-stream")
+                .send()
+                .unwrap()
+                .text()
+                .unwrap()
+        };
+        let versions = || {
+            let res = Client::new()
+                .get(&versions_url)
+                .bearer_auth("sekreto")
+                .send()
+                .unwrap();
+            assert_eq!(res.status().as_u16(), 200);
+            let body: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+            let sizes: Vec<(u64, u64)> = body["versions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| (v["version"].as_u64().unwrap(), v["size"].as_u64().unwrap()))
+                .collect();
+            (body["current"]["size"].as_u64(), sizes)
+        };
+        let restore = |version: u32| {
+            Client::new()
+                .post(format!("{}/{}/restore", versions_url, version))
+                .bearer_auth("sekreto")
+                .send()
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+
+        upload("1");
+        assert_eq!(versions(), (Some(1), vec![]));
+        upload("22");
+        upload("333");
+        upload("4444");
+        // Only the last two previous versions are kept
+        assert_eq!(versions(), (Some(4), vec![(2, 2), (3, 3)]));
+
+        assert_eq!(restore(1), 404);
+        assert_eq!(restore(2), 204);
+        assert_eq!(download(), "22");
+        assert_eq!(versions(), (Some(2), vec![(3, 3), (4, 4)]));
+
+        // Deleting a backup deletes all of its versions
+        let res = Client::new()
+            .delete(&backup_url)
+            .header(header::USER_AGENT, "Threema")
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 204);
+        let res = Client::new()
+            .get(&versions_url)
+            .bearer_auth("sekreto")
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 404);
+    }
+}
+
+#[test]
+fn admin_trash_untrash() {
+    for storage in [None, Some(StorageConfig::Memory)] {
+        let server = TestServer::with_config(|config| {
+            config.storage = storage;
+            config.keep_versions = Some(1);
+            config.trash_days = Some(7);
+            config.admin_token = Some("sekreto".into());
+        });
+        let backup_id = "c".repeat(64);
+        let backup_url = format!("{}/backups/{}", server.base_url, backup_id);
+        let request = |method, url: &str| {
+            Client::new()
+                .request(method, url)
+                .header(header::USER_AGENT, "Threema")
+                .header(header::ACCEPT, "application/octet-stream")
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .bearer_auth("sekreto")
+        };
+        let trash = || {
+            let res = request(Method::GET, &format!("{}/admin/trash", server.base_url))
+                .send()
+                .unwrap();
+            assert_eq!(res.status().as_u16(), 200);
+            let body: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+            body.as_array().unwrap().clone()
+        };
+        let untrash = || {
+            request(
+                Method::POST,
+                &format!("{}/admin/trash/{}/restore", server.base_url, backup_id),
+            )
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+        };
+
+        assert_eq!(untrash(), 404);
+        for body in ["unua", "dua"] {
+            let res = request(Method::PUT, &backup_url).body(body).send().unwrap();
+            assert!(res.status().is_success());
+        }
+        assert!(trash().is_empty());
+
+        // Deleted backups are moved to the trash
+        let res = request(Method::DELETE, &backup_url).send().unwrap();
+        assert_eq!(res.status().as_u16(), 204);
+        let res = request(Method::GET, &backup_url).send().unwrap();
+        assert_eq!(res.status().as_u16(), 404);
+        let trash = trash();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0]["backupId"], backup_id.as_str());
+        assert_eq!(trash[0]["size"], 3);
+        assert_eq!(
+            trash[0]["purged"].as_u64().unwrap() - trash[0]["deleted"].as_u64().unwrap(),
+            7 * 24 * 3600
+        );
+
+        // They can be restored along with their versions
+        assert_eq!(untrash(), 204);
+        let res = request(Method::GET, &backup_url).send().unwrap();
+        assert_eq!(res.text().unwrap(), "dua");
+        let res = request(
+            Method::GET,
+            &format!("{}/admin/backups/{}/versions", server.base_url, backup_id),
+        )
+        .send()
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+        assert_eq!(body["versions"][0]["size"], 4);
+
+        // Unless a new backup was uploaded in the meantime
+        let res = request(Method::DELETE, &backup_url).send().unwrap();
+        assert_eq!(res.status().as_u16(), 204);
+        let res = request(Method::PUT, &backup_url)
+            .body("tri")
+            .send()
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 201);
+        assert_eq!(untrash(), 409);
+    }
+}
+
+#[test]
+fn admin_stats() {
+    let server = TestServer::with_config(|config| config.admin_token = Some("sekreto".into()));
+    let url = format!("{}/admin/stats", server.base_url);
+    let res = Client::new().get(&url).send().unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+
+    for (backup_id, size) in [("a", 100), ("b", 300)] {
+        let res = upload_backup(&server.base_url, &backup_id.repeat(64), vec![0; size]);
+        assert_eq!(res.status().as_u16(), 201);
+    }
+    let res = Client::new()
+        .get(&url)
+        .bearer_auth("sekreto")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let stats: serde_json::Value = serde_json::from_str(&res.text().unwrap()).unwrap();
+    assert_eq!(stats["backups"], 2);
+    assert_eq!(stats["totalBytes"], 400);
+    assert_eq!(stats["averageBytes"], 200);
+    assert_eq!(stats["sizePercentiles"]["max"], 300);
+    assert_eq!(stats["ageHistogram"][0]["backups"], 2);
+    assert_eq!(stats["ageHistogram"][4]["maxDays"], serde_json::Value::Null);
+    assert_eq!(stats["nearExpiry"]["backups"], 0);
+    assert_eq!(stats["largest"][0]["backupId"], "b".repeat(64));
+    assert_eq!(stats["largest"][0]["expiresInDays"], 180);
+
+    // The CLI reports the same statistics
+    let output = run_cli(server.backup_dir.path(), &["stats", "--json"]);
+    assert!(output.status.success());
+    let cli_stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(cli_stats["backups"], 2);
+    assert_eq!(cli_stats["largest"], stats["largest"]);
+}
+
+#[test]
+fn cli_check_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_cli(dir.path(), &["check-config"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("The configuration is valid"),
+        "{}",
+        stdout
+    );
+
+    // All problems are listed at once
+    let config_path = dir.path().join("invalid.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "max_backup_bytes = 0\nretention_days = 180\nbackup_dir = {:?}\n\
+             io_threads = 4\nlisten_on = \"localhost\"\n",
+            dir.path().join("missing")
+        ),
+    )
+    .unwrap();
+    let output = run_cli_with_config(&config_path, &["check-config"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Warning: The setting `io_threads` is deprecated"));
+    assert!(stderr.contains("- max_backup_bytes must be greater than 0\n"));
+    assert!(stderr.contains("- listen_on \"localhost\" is not a valid address"));
+    assert!(stderr.contains("missing\" does not exist\n"));
+    assert!(stderr.contains("Found 3 problem(s)"), "{}", stderr);
+
+    // Unknown settings are listed with the other problems
+    std::fs::write(
+        &config_path,
+        format!(
+            "max_backup_bytes = 0\nretention_days = 1\nbackup_dir = {:?}\n\
+             listen_on = \"127.0.0.1:0\"\nretention = 180\n\
+             [storage]\nbackend = \"filesystem\"\nshard = true\n",
+            dir.path()
+        ),
+    )
+    .unwrap();
+    let output = run_cli_with_config(&config_path, &["check-config"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("- unknown field `retention`\n"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("- unknown field `storage.shard`\n"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("- max_backup_bytes must be greater than 0\n"));
+    assert!(stderr.contains("Found 3 problem(s)"), "{}", stderr);
+
+    // Other commands reject unknown settings
+    let output = run_cli_with_config(&config_path, &["list"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("unknown field `retention`"), "{}", stderr);
+}
+
+#[test]
+fn cli_config_from_env() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_sekursranko"))
+        .env("SEKURSRANKO_MAX_BACKUP_BYTES", "524288")
+        .env("SEKURSRANKO_RETENTION_DAYS", "180")
+        .env("SEKURSRANKO_BACKUP_DIR", dir.path())
+        .env("SEKURSRANKO_LISTEN_ON", "127.0.0.1:1")
+        .env("SEKURSRANKO_STORAGE__BACKEND", "filesystem")
+        .args(["--set", "listen_on=127.0.0.1:0", "-s", "storage.fsync=true"])
+        .arg("check-config")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("- Listening address: 127.0.0.1:0\n"));
+    assert!(stdout.contains("- Storage backend: filesystem (flat, fsync)\n"));
+
+    // The config file is overridden by environment variables
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "max_backup_bytes = 0\nretention_days = 180\nbackup_dir = {:?}\n\
+             listen_on = \"127.0.0.1:0\"\n",
+            dir.path()
+        ),
+    )
+    .unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_sekursranko"))
+        .env("SEKURSRANKO_CONFIG", &config_path)
+        .env("SEKURSRANKO_MAX_BACKUP_BYTES", "1000")
+        .arg("check-config")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("- Max backup bytes: 1000\n"));
+}
+
+#[test]
+fn config_reload() {
+    let TestServer {
+        base_url,
+        backup_dir: _backup_dir,
+        config,
+        reloadable_config,
+        ..
+    } = TestServer::new();
+    let backup_id = "1".repeat(64);
+    let res = upload_backup(&base_url, &backup_id, vec![0; 2000]);
+    assert_eq!(res.status().as_u16(), 201);
+
+    // Reloadable settings apply to the following requests
+    let changes = reloadable_config
+        .reload(ServerConfig {
+            max_backup_bytes: 1000,
+            allow_browser: Some(true),
+            ..config.clone()
+        })
+        .unwrap();
+    assert_eq!(changes.len(), 2);
+    let res = upload_backup(&base_url, &backup_id, vec![0; 2000]);
+    assert_eq!(res.status().as_u16(), 413);
+    let res = Client::new()
+        .get(format!("{}/config", base_url))
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        res.text().unwrap(),
+        "{\"maxBackupBytes\":1000,\"retentionDays\":180}"
+    );
+
+    // Other settings require a restart
+    let res = reloadable_config.reload(ServerConfig {
+        max_backup_bytes: 2000,
+        retention_days: 90,
+        ..config
+    });
+    assert_eq!(
+        res.unwrap_err(),
+        "Changing retention_days requires a restart"
+    );
+    assert_eq!(reloadable_config.get().max_backup_bytes, 1000);
+}